@@ -0,0 +1,107 @@
+//! The fields the formatter writes itself, and the keys it writes them under.
+
+use std::borrow::Cow;
+
+/// A field written by the formatter itself, rather than recorded on an event
+/// or span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Builtin {
+    Timestamp,
+    Level,
+    Target,
+    Span,
+    SpanPath,
+    SpanId,
+    ParentSpanId,
+    RootSpanId,
+    File,
+    Line,
+    ModulePath,
+    ThreadName,
+    ThreadId,
+    Pid,
+    Hostname,
+    /// An object with the file, line and module path the event was recorded
+    /// in.
+    SourceLocation,
+    /// The trace id from the OpenTelemetry context, or from the field set
+    /// with `with_trace_id_field`.
+    TraceId,
+    /// The object span fields are grouped under by
+    /// [`Layout::NestedBySpan`](crate::Layout::NestedBySpan).
+    Spans,
+    /// The object event fields are grouped under by
+    /// [`Layout::FieldsObject`](crate::Layout::FieldsObject) and
+    /// [`Layout::AllFieldsObject`](crate::Layout::AllFieldsObject).
+    Fields,
+}
+
+impl Builtin {
+    pub(crate) const ALL: [Builtin; 19] = [
+        Builtin::Timestamp,
+        Builtin::Level,
+        Builtin::Target,
+        Builtin::Span,
+        Builtin::SpanPath,
+        Builtin::SpanId,
+        Builtin::ParentSpanId,
+        Builtin::RootSpanId,
+        Builtin::File,
+        Builtin::Line,
+        Builtin::ModulePath,
+        Builtin::ThreadName,
+        Builtin::ThreadId,
+        Builtin::Pid,
+        Builtin::Hostname,
+        Builtin::SourceLocation,
+        Builtin::TraceId,
+        Builtin::Spans,
+        Builtin::Fields,
+    ];
+
+    /// The key this field is written under unless it is renamed.
+    pub fn default_key(self) -> &'static str {
+        match self {
+            Builtin::Timestamp => "timestamp",
+            Builtin::Level => "level",
+            Builtin::Target => "target",
+            Builtin::Span => "span",
+            Builtin::SpanPath => "span_path",
+            Builtin::SpanId => "span_id",
+            Builtin::ParentSpanId => "parent_span_id",
+            Builtin::RootSpanId => "root_span_id",
+            Builtin::File => "file",
+            Builtin::Line => "line",
+            Builtin::ModulePath => "module_path",
+            Builtin::ThreadName => "thread_name",
+            Builtin::ThreadId => "thread_id",
+            Builtin::Pid => "pid",
+            Builtin::Hostname => "hostname",
+            Builtin::SourceLocation => "source_location",
+            Builtin::TraceId => "trace_id",
+            Builtin::Spans => "spans",
+            Builtin::Fields => "fields",
+        }
+    }
+}
+
+/// The key each built-in field is written under.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BuiltinKeys {
+    renamed: Vec<(Builtin, Cow<'static, str>)>,
+}
+
+impl BuiltinKeys {
+    pub(crate) fn get(&self, builtin: Builtin) -> &str {
+        self.renamed
+            .iter()
+            .find(|(renamed, _)| *renamed == builtin)
+            .map_or(builtin.default_key(), |(_, key)| key)
+    }
+
+    pub(crate) fn set(&mut self, builtin: Builtin, key: impl Into<Cow<'static, str>>) {
+        self.renamed.retain(|(renamed, _)| *renamed != builtin);
+        self.renamed.push((builtin, key.into()));
+    }
+}
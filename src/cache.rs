@@ -0,0 +1,196 @@
+//! Caches the fields of each span so they are only serialized once.
+
+#[cfg(feature = "redact")]
+use std::sync::Arc;
+
+use tracing::{
+    span::{Attributes, Id, Record},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::fields::{FieldValue, FieldVisitor};
+#[cfg(feature = "redact")]
+use crate::Redactor;
+use crate::SolinkJsonFormat;
+
+/// `Layer` that records the fields of every span, already serialized, in the
+/// span's extensions.
+///
+/// When it is installed, `SolinkJsonFormat` copies span fields straight from
+/// this cache instead of parsing each span's `FormattedFields` for every
+/// event:
+///
+/// ```rs
+///     tracing_subscriber::registry()
+///         .with(SpanFieldCache::new())
+///         .with(log_to_file)
+///         .init();
+/// ```
+///
+/// With [`for_format`](Self::for_format), span fields are also redacted
+/// once, when they are recorded, rather than for every event.
+#[derive(Debug, Default)]
+pub struct SpanFieldCache {
+    /// Redacts span fields once, as they are recorded.
+    #[cfg(feature = "redact")]
+    redactor: Option<Arc<Redactor>>,
+}
+
+impl SpanFieldCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache that also redacts span fields with the redactor of `format`
+    /// when they are recorded, so that `format` doesn't have to redact them
+    /// again for every event.
+    pub fn for_format(format: &SolinkJsonFormat) -> Self {
+        #[cfg(not(feature = "redact"))]
+        let _ = format;
+        Self {
+            #[cfg(feature = "redact")]
+            redactor: format.redactor().cloned(),
+        }
+    }
+}
+
+impl<S> Layer<S> for SpanFieldCache
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut ext = span.extensions_mut();
+        if ext.get_mut::<CachedSpanFields>().is_some() {
+            return;
+        }
+
+        let mut fields = CachedSpanFields {
+            #[cfg(feature = "redact")]
+            redactor: self.redactor.clone(),
+            ..CachedSpanFields::default()
+        };
+        attrs.record(&mut FieldVisitor::new(|key, value| {
+            fields.record(key, value)
+        }));
+        ext.insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut ext = span.extensions_mut();
+        if let Some(fields) = ext.get_mut::<CachedSpanFields>() {
+            values.record(&mut FieldVisitor::new(|key, value| {
+                fields.record(key, value)
+            }));
+        }
+    }
+}
+
+/// Span extension holding the span's fields in the order they were first
+/// recorded.
+#[derive(Debug, Default)]
+pub(crate) struct CachedSpanFields {
+    fields: Vec<CachedField>,
+    /// The redactor each field was also redacted with, if any.
+    #[cfg(feature = "redact")]
+    redactor: Option<Arc<Redactor>>,
+}
+
+#[derive(Debug)]
+struct CachedField {
+    key: &'static str,
+    value: FieldValue,
+    /// The value once redacted, or `None` if the redactor leaves it out.
+    #[cfg(feature = "redact")]
+    redacted: Option<FieldValue>,
+}
+
+impl CachedSpanFields {
+    /// Record a field, replacing any earlier value with the same name.
+    pub(crate) fn record(&mut self, key: &'static str, value: serde_json::Value) {
+        let field = CachedField {
+            key,
+            value: FieldValue::cached(value),
+            #[cfg(feature = "redact")]
+            redacted: None,
+        };
+        #[cfg(feature = "redact")]
+        let field = match &self.redactor {
+            Some(redactor) => CachedField {
+                redacted: redactor.redact(key, field.value.clone()).map(
+                    |redacted| match redacted {
+                        FieldValue::Value(value) => FieldValue::cached(value),
+                        cached => cached,
+                    },
+                ),
+                ..field
+            },
+            None => field,
+        };
+        match self.fields.iter_mut().find(|existing| existing.key == key) {
+            Some(existing) => *existing = field,
+            None => self.fields.push(field),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&'static str, &FieldValue)> {
+        self.fields.iter().map(|field| (field.key, &field.value))
+    }
+
+    /// The fields as redacted by `redactor` when they were recorded, if this
+    /// cache redacted them with it.
+    #[cfg(feature = "redact")]
+    pub(crate) fn redacted_by(
+        &self,
+        redactor: &Arc<Redactor>,
+    ) -> Option<impl Iterator<Item = (&'static str, &FieldValue)>> {
+        let cached = self.redactor.as_ref()?;
+        if !Arc::ptr_eq(cached, redactor) {
+            return None;
+        }
+        Some(self.fields.iter().filter_map(|field| {
+            let value = field.redacted.as_ref()?;
+            Some((field.key, value))
+        }))
+    }
+}
+
+#[cfg(all(test, feature = "redact"))]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn should_redact_once_when_recorded() {
+        let redactor = Arc::new(Redactor::new().redact_key("password"));
+        let mut fields = CachedSpanFields {
+            redactor: Some(redactor.clone()),
+            ..CachedSpanFields::default()
+        };
+        fields.record("user", json!("bob"));
+        fields.record("password", json!("hunter2"));
+
+        let values = |fields: &mut dyn Iterator<Item = (&'static str, &FieldValue)>| {
+            fields
+                .map(|(key, value)| (key, value.as_value().clone()))
+                .collect::<Vec<(&str, Value)>>()
+        };
+        assert_eq!(
+            values(&mut fields.redacted_by(&redactor).unwrap()),
+            [("user", json!("bob")), ("password", json!("[REDACTED]"))],
+        );
+        assert_eq!(
+            values(&mut fields.iter()),
+            [("user", json!("bob")), ("password", json!("hunter2"))],
+        );
+        let other = Arc::new(Redactor::new().redact_key("password"));
+        assert!(fields.redacted_by(&other).is_none());
+    }
+}
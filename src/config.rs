@@ -0,0 +1,648 @@
+//! Building a `SolinkJsonFormat` from a config file or environment variables.
+
+use std::{collections::BTreeMap, error::Error, fmt};
+
+use chrono::SecondsFormat;
+use serde::{de, Deserialize, Deserializer};
+use serde_json::{Map, Value};
+
+use crate::{
+    CollisionPolicy, ErrorStrategy, IdFormat, Layout, LevelFormat, SolinkJsonFormat, SpanPath,
+    TimestampFormat,
+};
+#[cfg(feature = "redact")]
+use crate::{Redactor, Replacement, ValueScanner};
+
+/// The options of a [`SolinkJsonFormat`], as read from a config file or the
+/// environment, so they can be changed without recompiling.
+///
+/// It can be deserialized from any format serde supports, such as TOML:
+///
+/// ```toml
+/// timestamp_format = "unix_millis"
+/// target = false
+/// span_path = ">"
+/// hostname = true
+/// renames = { level = "severity", message = "msg" }
+/// static_fields = { service = "orders" }
+/// redact_keys = ["password"]
+/// redact_key_globs = ["*_token"]
+/// redact_scanners = ["email", "card_number"]
+/// ```
+///
+/// Or from environment variables named after each option, with a prefix,
+/// such as `LOG_TIMESTAMP_FORMAT=unix_millis`. Values are parsed as JSON if
+/// they can be, lists can also be written as `a,b`, and maps as `a=b,c=d`.
+///
+/// Every option is optional, and defaults to what `SolinkJsonFormat::new()`
+/// does. Errors name the option, or the environment variable, that is
+/// invalid.
+#[derive(Debug, Clone, Default)]
+pub struct FormatConfig {
+    options: Options,
+    /// The prefix of the environment variables the options were read from.
+    env_prefix: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Options {
+    timestamp: Option<bool>,
+    timestamp_format: Option<String>,
+    target: Option<bool>,
+    span_name: Option<bool>,
+    /// `array`, or the separator to join span names with.
+    span_path: Option<String>,
+    span_id: Option<bool>,
+    parent_span_id: Option<bool>,
+    root_span_id: Option<bool>,
+    id_format: Option<String>,
+    trace_id_prefix: Option<String>,
+    trace_id_field: Option<String>,
+    level_format: Option<String>,
+    required_message: Option<bool>,
+    source_location: Option<bool>,
+    file: Option<bool>,
+    line_number: Option<bool>,
+    module_path: Option<bool>,
+    thread_name: Option<bool>,
+    thread_id: Option<bool>,
+    pid: Option<bool>,
+    hostname: Option<bool>,
+    #[serde(deserialize_with = "map_or_pairs")]
+    renames: Option<BTreeMap<String, String>>,
+    #[serde(deserialize_with = "map_or_pairs")]
+    static_fields: Option<BTreeMap<String, Value>>,
+    /// Static fields read from environment variables, by field name.
+    #[serde(deserialize_with = "map_or_pairs")]
+    env_fields: Option<BTreeMap<String, String>>,
+    service_fields_from_env: Option<bool>,
+    #[cfg(feature = "redact")]
+    #[serde(deserialize_with = "list_or_string")]
+    redact_keys: Option<Vec<String>>,
+    #[cfg(feature = "redact")]
+    #[serde(deserialize_with = "list_or_string")]
+    redact_keys_ignore_case: Option<Vec<String>>,
+    #[cfg(feature = "redact")]
+    #[serde(deserialize_with = "list_or_string")]
+    redact_key_globs: Option<Vec<String>>,
+    /// `email`, `card_number` or `bearer_token`.
+    #[cfg(feature = "redact")]
+    #[serde(deserialize_with = "list_or_string")]
+    redact_scanners: Option<Vec<String>>,
+    /// Regular expressions to redact matches of.
+    #[cfg(feature = "redact")]
+    #[serde(deserialize_with = "list_or_string")]
+    redact_patterns: Option<Vec<String>>,
+    /// `mask`, `drop` or `hash`.
+    #[cfg(feature = "redact")]
+    redact_replacement: Option<String>,
+    #[cfg(feature = "redact")]
+    redact_mask: Option<String>,
+    #[cfg(feature = "redact")]
+    redact_hash_salt: Option<String>,
+    layout: Option<String>,
+    collision_policy: Option<String>,
+    error_strategy: Option<String>,
+}
+
+impl FormatConfig {
+    /// Read the options from the environment variables that start with
+    /// `prefix`, such as `LOG_` for `LOG_TARGET=false`.
+    ///
+    /// Variables with the prefix that are not options are ignored, since
+    /// they may well be meant for something else, such as `LOG_DIR`.
+    pub fn from_env(prefix: &str) -> Result<Self, ConfigError> {
+        let names = option_names();
+        let options = std::env::vars()
+            .filter_map(|(var, value)| {
+                let option = var.strip_prefix(prefix)?.to_ascii_lowercase();
+                if !names.contains(&option.as_str()) {
+                    return None;
+                }
+                // `LOG_REDACT_MASK=123` is still a string.
+                let value = match serde_json::from_str(&value) {
+                    Ok(json) if parse(&option, &json).is_ok() => json,
+                    _ => Value::String(value),
+                };
+                Some((option, value))
+            })
+            .collect();
+        Self::from_options(options, Some(prefix.to_string()))
+    }
+
+    fn from_options(
+        options: Map<String, Value>,
+        env_prefix: Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let config = Self {
+            options: Options::default(),
+            env_prefix,
+        };
+        // Check each option on its own first, so an error can name it.
+        for (option, value) in &options {
+            if let Err(error) = parse(option, value) {
+                return Err(config.error(option, error));
+            }
+        }
+        let options = serde_json::from_value(Value::Object(options))
+            .map_err(|error| config.error("", error))?;
+        Ok(Self { options, ..config })
+    }
+
+    /// Build the format, checking every option is valid.
+    pub fn build(self) -> Result<SolinkJsonFormat, ConfigError> {
+        let o = &self.options;
+        let mut format = SolinkJsonFormat::new();
+
+        let flags: [(Option<bool>, SetFlag); 16] = [
+            (o.timestamp, SolinkJsonFormat::with_timestamp),
+            (o.target, SolinkJsonFormat::with_target),
+            (o.span_name, SolinkJsonFormat::with_span_name),
+            (o.span_id, SolinkJsonFormat::with_span_id),
+            (o.parent_span_id, SolinkJsonFormat::with_parent_span_id),
+            (o.root_span_id, SolinkJsonFormat::with_root_span_id),
+            (o.required_message, SolinkJsonFormat::with_required_message),
+            (o.source_location, SolinkJsonFormat::with_source_location),
+            (o.file, SolinkJsonFormat::with_file),
+            (o.line_number, SolinkJsonFormat::with_line_number),
+            (o.module_path, SolinkJsonFormat::with_module_path),
+            (o.thread_name, SolinkJsonFormat::with_thread_name),
+            (o.thread_id, SolinkJsonFormat::with_thread_id),
+            (o.pid, SolinkJsonFormat::with_pid),
+            (o.hostname, SolinkJsonFormat::with_hostname),
+            (o.service_fields_from_env, |format, add| match add {
+                true => format.with_service_fields_from_env(),
+                false => format,
+            }),
+        ];
+        for (value, set) in flags {
+            if let Some(value) = value {
+                format = set(format, value);
+            }
+        }
+
+        if let Some(value) = &o.timestamp_format {
+            let timestamp_format =
+                match value.as_str() {
+                    "rfc3339" | "rfc3339_nanos" => TimestampFormat::Rfc3339(SecondsFormat::Nanos),
+                    "rfc3339_micros" => TimestampFormat::Rfc3339(SecondsFormat::Micros),
+                    "rfc3339_millis" => TimestampFormat::Rfc3339(SecondsFormat::Millis),
+                    "rfc3339_secs" => TimestampFormat::Rfc3339(SecondsFormat::Secs),
+                    "rfc3339_local" => TimestampFormat::Rfc3339Local(SecondsFormat::Nanos),
+                    "unix_seconds" => TimestampFormat::UnixSeconds,
+                    "unix_millis" => TimestampFormat::UnixMillis,
+                    "unix_micros" => TimestampFormat::UnixMicros,
+                    "unix_nanos" => TimestampFormat::UnixNanos,
+                    _ => return Err(self.invalid(
+                        "timestamp_format",
+                        value,
+                        "rfc3339, rfc3339_nanos, rfc3339_micros, rfc3339_millis, rfc3339_secs, \
+                         rfc3339_local, unix_seconds, unix_millis, unix_micros or unix_nanos",
+                    )),
+                };
+            format = format.with_timestamp_format(timestamp_format);
+        }
+        if let Some(value) = &o.span_path {
+            let span_path = match value.as_str() {
+                "array" => SpanPath::Array,
+                "" => return Err(self.invalid("span_path", value, "array or a separator")),
+                separator => SpanPath::Joined(separator.to_string().into()),
+            };
+            format = format.with_span_path(Some(span_path));
+        }
+        if let Some(value) = &o.id_format {
+            let id_format = match value.as_str() {
+                "number" => IdFormat::Number,
+                "hex" => IdFormat::Hex,
+                _ => return Err(self.invalid("id_format", value, "number or hex")),
+            };
+            format = format.with_id_format(id_format);
+        }
+        if let Some(prefix) = &o.trace_id_prefix {
+            format = format.with_trace_id(Some(prefix.as_str()));
+        }
+        if let Some(key) = &o.trace_id_field {
+            format = format.with_trace_id_field(key);
+        }
+        if let Some(value) = &o.level_format {
+            let level_format = match value.as_str() {
+                "uppercase" => LevelFormat::Uppercase,
+                "lowercase" => LevelFormat::Lowercase,
+                "gcp" => LevelFormat::Gcp,
+                "bunyan" => LevelFormat::Bunyan,
+                _ => {
+                    return Err(self.invalid(
+                        "level_format",
+                        value,
+                        "uppercase, lowercase, gcp or bunyan",
+                    ))
+                }
+            };
+            format = format.with_level_format(level_format);
+        }
+        if let Some(renames) = &o.renames {
+            format = format.with_renames(renames.clone());
+        }
+        for (key, value) in o.static_fields.iter().flatten() {
+            format = format
+                .try_with_static_field(key, value)
+                .map_err(|error| self.error("static_fields", error))?;
+        }
+        for (key, var) in o.env_fields.iter().flatten() {
+            format = format.with_env_field(key, var);
+        }
+        #[cfg(feature = "redact")]
+        if let Some(redactor) = self.redactor()? {
+            format = format.with_redactor(redactor);
+        }
+        if let Some(value) = &o.layout {
+            let layout =
+                match value.as_str() {
+                    "flat" => Layout::Flat,
+                    "prefixed_flat" => Layout::PrefixedFlat,
+                    "nested_by_span" => Layout::NestedBySpan,
+                    "fields_object" => Layout::FieldsObject,
+                    "all_fields_object" => Layout::AllFieldsObject,
+                    _ => return Err(self.invalid(
+                        "layout",
+                        value,
+                        "flat, prefixed_flat, nested_by_span, fields_object or all_fields_object",
+                    )),
+                };
+            format = format.with_layout(layout);
+        }
+        if let Some(value) = &o.collision_policy {
+            let collision_policy = match value.as_str() {
+                "innermost_wins" => CollisionPolicy::InnermostWins,
+                "outermost_wins" => CollisionPolicy::OutermostWins,
+                "keep_all" => CollisionPolicy::KeepAll,
+                "prefix_with_span" => CollisionPolicy::PrefixWithSpan,
+                _ => {
+                    return Err(self.invalid(
+                        "collision_policy",
+                        value,
+                        "innermost_wins, outermost_wins, keep_all or prefix_with_span",
+                    ))
+                }
+            };
+            format = format.with_collision_policy(collision_policy);
+        }
+        if let Some(value) = &o.error_strategy {
+            let error_strategy = match value.as_str() {
+                "drop" => ErrorStrategy::Drop,
+                "fallback" => ErrorStrategy::Fallback,
+                "report_to_stderr" => ErrorStrategy::ReportToStderr,
+                _ => {
+                    return Err(self.invalid(
+                        "error_strategy",
+                        value,
+                        "drop, fallback or report_to_stderr",
+                    ))
+                }
+            };
+            format = format.with_error_strategy(error_strategy);
+        }
+
+        Ok(format)
+    }
+
+    /// The redactor the options describe, if they set any redaction rule.
+    #[cfg(feature = "redact")]
+    fn redactor(&self) -> Result<Option<Redactor>, ConfigError> {
+        let o = &self.options;
+        let rules = [
+            &o.redact_keys,
+            &o.redact_keys_ignore_case,
+            &o.redact_key_globs,
+            &o.redact_scanners,
+            &o.redact_patterns,
+        ];
+        if rules.iter().all(|rule| rule.is_none()) {
+            return Ok(None);
+        }
+
+        let mut redactor = Redactor::new();
+        for key in o.redact_keys.iter().flatten() {
+            redactor = redactor.redact_key(key);
+        }
+        for key in o.redact_keys_ignore_case.iter().flatten() {
+            redactor = redactor.redact_key_ignore_case(key);
+        }
+        for pattern in o.redact_key_globs.iter().flatten() {
+            redactor = redactor.redact_key_glob(pattern);
+        }
+        for name in o.redact_scanners.iter().flatten() {
+            let scanner = match name.as_str() {
+                "email" => ValueScanner::email(),
+                "card_number" => ValueScanner::card_number(),
+                "bearer_token" => ValueScanner::bearer_token(),
+                _ => {
+                    return Err(self.invalid(
+                        "redact_scanners",
+                        name,
+                        "email, card_number or bearer_token",
+                    ))
+                }
+            };
+            redactor = redactor.scan_values(scanner);
+        }
+        for pattern in o.redact_patterns.iter().flatten() {
+            let scanner =
+                ValueScanner::new(pattern).map_err(|error| self.error("redact_patterns", error))?;
+            redactor = redactor.scan_values(scanner);
+        }
+
+        let replacement = match o.redact_replacement.as_deref() {
+            None | Some("mask") => match &o.redact_mask {
+                Some(mask) => Replacement::Mask(mask.clone()),
+                None => Replacement::default(),
+            },
+            Some("drop") => Replacement::Drop,
+            Some("hash") => match &o.redact_hash_salt {
+                Some(salt) => Replacement::Hash {
+                    salt: salt.clone().into_bytes(),
+                },
+                None => return Err(self.error("redact_hash_salt", "required to hash")),
+            },
+            Some(value) => {
+                return Err(self.invalid("redact_replacement", value, "mask, drop or hash"))
+            }
+        };
+        Ok(Some(redactor.with_replacement(replacement)))
+    }
+
+    fn invalid(&self, option: &str, value: &str, expected: &str) -> ConfigError {
+        self.error(
+            option,
+            format!("unknown value `{}`, expected {}", value, expected),
+        )
+    }
+
+    fn error(&self, option: &str, message: impl fmt::Display) -> ConfigError {
+        let option = match &self.env_prefix {
+            Some(prefix) => format!("{}{}", prefix, option.to_ascii_uppercase()),
+            None => option.to_string(),
+        };
+        ConfigError {
+            option,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FormatConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let options = Map::deserialize(deserializer)?;
+        Self::from_options(options, None).map_err(de::Error::custom)
+    }
+}
+
+impl SolinkJsonFormat {
+    /// Build a format from the environment variables that start with
+    /// `prefix`. See [`FormatConfig`] for the options.
+    pub fn from_env(prefix: &str) -> Result<Self, ConfigError> {
+        FormatConfig::from_env(prefix)?.build()
+    }
+}
+
+impl<'de> Deserialize<'de> for SolinkJsonFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        FormatConfig::deserialize(deserializer)?
+            .build()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A builder method that turns a field on or off.
+type SetFlag = fn(SolinkJsonFormat, bool) -> SolinkJsonFormat;
+
+/// An invalid option in a [`FormatConfig`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    option: String,
+    message: String,
+}
+
+impl ConfigError {
+    /// The name of the invalid option, or of the environment variable it was
+    /// read from.
+    pub fn option(&self) -> &str {
+        &self.option
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.option.as_str() {
+            "" => write!(f, "invalid log format config: {}", self.message),
+            option => write!(
+                f,
+                "invalid log format option `{}`: {}",
+                option, self.message
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// The name of every option.
+fn option_names() -> &'static [&'static str] {
+    /// Deserializer that only records the names of a struct's fields.
+    struct FieldNames<'n>(&'n mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for FieldNames<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("expected a struct"))
+        }
+
+        fn deserialize_struct<V: de::Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("only the field names are read"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut names: &'static [&'static str] = &[];
+    let _ = Options::deserialize(FieldNames(&mut names));
+    names
+}
+
+/// Parse a single option.
+fn parse(option: &str, value: &Value) -> Result<Options, serde_json::Error> {
+    let single = Map::from_iter([(option.to_string(), value.clone())]);
+    serde_json::from_value(Value::Object(single))
+}
+
+/// A list, or a string of comma-separated items.
+#[cfg(feature = "redact")]
+fn list_or_string<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ListOrString {
+        List(Vec<String>),
+        String(String),
+    }
+
+    Ok(Some(match ListOrString::deserialize(deserializer)? {
+        ListOrString::List(items) => items,
+        ListOrString::String(items) => items
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+    }))
+}
+
+/// A map, or a string of comma-separated `key=value` pairs.
+fn map_or_pairs<'de, D, V>(deserializer: D) -> Result<Option<BTreeMap<String, V>>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de> + From<String>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MapOrPairs<V> {
+        Map(BTreeMap<String, V>),
+        Pairs(String),
+    }
+
+    let pairs = match MapOrPairs::deserialize(deserializer)? {
+        MapOrPairs::Map(map) => return Ok(Some(map)),
+        MapOrPairs::Pairs(pairs) => pairs,
+    };
+    pairs
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => Ok((key.trim().to_string(), value.trim().to_string().into())),
+            None => Err(de::Error::custom(format!(
+                "expected `key=value`, found `{}`",
+                pair
+            ))),
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{dispatcher, info};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{test_support::TestWriter, Clock, FlatJsonLayer};
+
+    fn capture(format: SolinkJsonFormat) -> String {
+        let writer = TestWriter::new();
+        let layer = FlatJsonLayer::new()
+            .with_format(format.with_clock(Clock::fixed("2024-06-18T21:17:44Z".parse().unwrap())))
+            .with_writer(writer.clone());
+
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, || {
+            let span = tracing::info_span!("handle", user = "bob@example.com");
+            let _s = span.enter();
+            info!(password = "hunter2", "Logged in")
+        });
+
+        writer.contents()
+    }
+
+    #[cfg(feature = "redact")]
+    #[test]
+    fn should_build_from_a_config_file() {
+        let format: SolinkJsonFormat = toml::from_str(
+            r#"
+            timestamp_format = "unix_millis"
+            target = false
+            renames = { level = "severity", message = "msg" }
+            static_fields = { service = "orders", v = 2 }
+            redact_keys = ["password"]
+            redact_scanners = "email"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            capture(format),
+            concat!(
+                r#"{"timestamp":1718745464000,"severity":"INFO","service":"orders","v":2,"#,
+                r#""msg":"Logged in","password":"[REDACTED]","span":"handle","#,
+                r#""user":"[REDACTED]"}"#,
+                "\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn should_build_from_the_environment() {
+        std::env::set_var("SOLINK_TEST_CONFIG_TIMESTAMP", "false");
+        std::env::set_var("SOLINK_TEST_CONFIG_LEVEL_FORMAT", "lowercase");
+        std::env::set_var("SOLINK_TEST_CONFIG_RENAMES", "message=msg,span=scope");
+        std::env::set_var("SOLINK_TEST_CONFIG_DIR", "/var/log/app");
+
+        assert_eq!(
+            capture(SolinkJsonFormat::from_env("SOLINK_TEST_CONFIG_").unwrap()),
+            concat!(
+                r#"{"level":"info","target":"solink_tracing_flat_json::config::tests","#,
+                r#""msg":"Logged in","password":"hunter2","scope":"handle","#,
+                r#""user":"bob@example.com"}"#,
+                "\n",
+            ),
+        );
+
+        std::env::set_var("SOLINK_TEST_CONFIG_LEVEL_FORMAT", "loud");
+        let error = FormatConfig::from_env("SOLINK_TEST_CONFIG_")
+            .unwrap()
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(error.option(), "SOLINK_TEST_CONFIG_LEVEL_FORMAT");
+        std::env::remove_var("SOLINK_TEST_CONFIG_LEVEL_FORMAT");
+    }
+
+    #[test]
+    fn should_name_the_invalid_option() {
+        let error = |json: &str| match serde_json::from_str::<SolinkJsonFormat>(json) {
+            Ok(_) => panic!("{} is valid", json),
+            Err(error) => error.to_string(),
+        };
+
+        assert_eq!(
+            error(r#"{"target": true, "span_id": "yes"}"#),
+            "invalid log format option `span_id`: invalid type: string \"yes\", expected a boolean",
+        );
+        assert!(error(r#"{"colour": true}"#)
+            .starts_with("invalid log format option `colour`: unknown field `colour`"));
+        assert_eq!(
+            error(r#"{"layout": "tree"}"#),
+            "invalid log format option `layout`: unknown value `tree`, expected flat, \
+             prefixed_flat, nested_by_span, fields_object or all_fields_object",
+        );
+        #[cfg(feature = "redact")]
+        assert_eq!(
+            error(r#"{"redact_patterns": ["("]}"#)
+                .lines()
+                .next()
+                .unwrap(),
+            "invalid log format option `redact_patterns`: regex parse error:",
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! The encodings each event can be written in, and how they are framed.
+
+use serde::Serialize;
+
+use crate::{fields::FlatFields, logfmt, FormatError};
+
+/// How each event is encoded, and how events are separated in the output.
+///
+/// Text encodings end each event with a newline. Binary encodings prefix
+/// each event with its length, as a 4-byte big-endian integer, so that a
+/// reader on the other end of a byte stream can split it back into events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Encoding {
+    /// A line of JSON. This is the default.
+    #[default]
+    Json,
+    /// A line of logfmt, as written by [`SolinkLogfmtFormat`](crate::SolinkLogfmtFormat).
+    Logfmt,
+    /// A length-prefixed MessagePack map.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// A length-prefixed CBOR map.
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Encoding {
+    /// Whether each event is written as a line of UTF-8 text.
+    pub fn is_text(self) -> bool {
+        matches!(self, Encoding::Json | Encoding::Logfmt)
+    }
+
+    /// Append `fields` to `buffer` as one frame.
+    pub(crate) fn encode_fields(
+        self,
+        fields: &FlatFields,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), FormatError> {
+        // Raw JSON fragments can only be copied into JSON output.
+        self.encode(&fields.serializable(self == Encoding::Json), buffer)?;
+        if self.is_text() {
+            std::str::from_utf8(buffer).map_err(FormatError::Utf8)?;
+        }
+        Ok(())
+    }
+
+    /// Append `value` to `buffer` as one frame.
+    fn encode(self, value: &impl Serialize, buffer: &mut Vec<u8>) -> Result<(), FormatError> {
+        match self {
+            Encoding::Json => {
+                serde_json::to_writer(&mut *buffer, value).map_err(FormatError::Serialize)?;
+                buffer.push(b'\n');
+            }
+            Encoding::Logfmt => {
+                logfmt::to_writer(&mut *buffer, value, false).map_err(FormatError::Serialize)?;
+                buffer.push(b'\n');
+            }
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => length_prefixed(buffer, |buffer| {
+                rmp_serde::encode::write(buffer, value)
+                    .map_err(|error| FormatError::Encode(error.into()))
+            })?,
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => length_prefixed(buffer, |buffer| {
+                ciborium::into_writer(value, buffer)
+                    .map_err(|error| FormatError::Encode(error.into()))
+            })?,
+        }
+        Ok(())
+    }
+}
+
+/// Append a frame written by `encode`, prefixed with its length.
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+fn length_prefixed(
+    buffer: &mut Vec<u8>,
+    encode: impl FnOnce(&mut Vec<u8>) -> Result<(), FormatError>,
+) -> Result<(), FormatError> {
+    let start = buffer.len();
+    buffer.extend_from_slice(&[0; 4]);
+    encode(buffer)?;
+
+    let len = u32::try_from(buffer.len() - start - 4)
+        .map_err(|error| FormatError::Encode(error.into()))?;
+    buffer[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tracing::{dispatcher, info};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{test_support::TestWriter, FlatJsonLayer, SolinkJsonFormat};
+
+    fn capture(encoding: Encoding) -> Vec<u8> {
+        let writer = TestWriter::new();
+        let layer = FlatJsonLayer::new()
+            .with_format(SolinkJsonFormat::new().with_timestamp(false))
+            .with_encoding(encoding)
+            .with_writer(writer.clone());
+
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, || {
+            let span = tracing::info_span!("handle", x = 7);
+            let _s = span.enter();
+            info!(y = [1, 2].len(), "First");
+            info!("Second");
+        });
+
+        let data = writer.data.lock().unwrap().clone();
+        data
+    }
+
+    /// Split length-prefixed frames, checking nothing is left over.
+    #[cfg(any(feature = "msgpack", feature = "cbor"))]
+    fn frames(mut data: &[u8]) -> Vec<&[u8]> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            frames.push(&data[4..4 + len]);
+            data = &data[4 + len..];
+        }
+        frames
+    }
+
+    fn expected() -> Vec<Value> {
+        let first = json!({
+            "level": "INFO",
+            "target": "solink_tracing_flat_json::encoding::tests",
+            "message": "First",
+            "y": 2,
+            "span": "handle",
+            "x": 7,
+        });
+        let mut second = first.clone();
+        second["message"] = "Second".into();
+        second.as_object_mut().unwrap().remove("y");
+        vec![first, second]
+    }
+
+    #[test]
+    fn should_write_json_lines() {
+        let data = capture(Encoding::Json);
+        let lines = std::str::from_utf8(&data).unwrap().lines();
+        let lines: Vec<Value> = lines
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, expected());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn should_write_length_prefixed_msgpack() {
+        let data = capture(Encoding::MessagePack);
+        let events: Vec<Value> = frames(&data)
+            .into_iter()
+            .map(|frame| rmp_serde::from_slice(frame).unwrap())
+            .collect();
+        assert_eq!(events, expected());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn should_write_length_prefixed_cbor() {
+        let data = capture(Encoding::Cbor);
+        let events: Vec<Value> = frames(&data)
+            .into_iter()
+            .map(|frame| ciborium::from_reader(frame).unwrap())
+            .collect();
+        assert_eq!(events, expected());
+    }
+}
@@ -0,0 +1,87 @@
+//! Handling of events that could not be formatted.
+
+use std::{
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Why an event could not be formatted.
+#[derive(Debug)]
+pub enum FormatError {
+    /// A span's recorded fields were not a JSON object. This usually means
+    /// the layer was not configured with `.fmt_fields(JsonFields::default())`.
+    SpanFields {
+        span: &'static str,
+        source: serde_json::Error,
+    },
+    /// The fields could not be serialized.
+    Serialize(serde_json::Error),
+    /// The serialized line was not valid UTF-8.
+    Utf8(std::str::Utf8Error),
+    /// The fields could not be encoded in a binary encoding.
+    Encode(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::SpanFields { span, source } => {
+                write!(
+                    f,
+                    "fields of span `{}` are not a JSON object: {}",
+                    span, source
+                )
+            }
+            FormatError::Serialize(source) => write!(f, "failed to serialize fields: {}", source),
+            FormatError::Utf8(source) => write!(f, "output is not valid UTF-8: {}", source),
+            FormatError::Encode(source) => write!(f, "failed to encode fields: {}", source),
+        }
+    }
+}
+
+impl Error for FormatError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FormatError::SpanFields { source, .. } => Some(source),
+            FormatError::Serialize(source) => Some(source),
+            FormatError::Utf8(source) => Some(source),
+            FormatError::Encode(source) => Some(source.as_ref()),
+        }
+    }
+}
+
+/// What to do with an event that could not be formatted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorStrategy {
+    /// Write nothing for the event.
+    Drop,
+    /// Write a line with just the built-in fields and the event's message,
+    /// plus a `log_format_error` field describing what failed. This is the
+    /// default.
+    #[default]
+    Fallback,
+    /// Write nothing for the event, and report the error on stderr instead.
+    ReportToStderr,
+}
+
+/// Counts the events a formatter failed to format.
+///
+/// Obtained from the formatter before it is handed to the subscriber, and
+/// can be cloned freely; every clone shares the same count.
+#[derive(Debug, Clone, Default)]
+pub struct FormatErrorCounter(Arc<AtomicU64>);
+
+impl FormatErrorCounter {
+    /// The number of events that failed to format so far.
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
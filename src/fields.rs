@@ -0,0 +1,694 @@
+//! Collects the fields of an event and its spans into a single flat map.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::{value::RawValue, Value};
+use tracing::field::{Field, Visit};
+
+#[cfg(feature = "redact")]
+use crate::Redactor;
+
+/// What to do when an event and its spans record fields with the same name.
+///
+/// Fields are visited from the innermost scope outwards: the event's own
+/// fields first, then the current span, then each of its parents in turn.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Keep the value from the innermost scope. This is the default.
+    #[default]
+    InnermostWins,
+    /// Keep the value from the outermost scope.
+    OutermostWins,
+    /// Emit every value in a JSON array, ordered from innermost to outermost.
+    KeepAll,
+    /// Keep the innermost value under the original key, and rename every
+    /// other occurrence to `<span name>.<key>`.
+    PrefixWithSpan,
+}
+
+/// How event and span fields are laid out in each line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// Every field at the top level:
+    /// `{"message":"Test","z":10,"span":"child","y":9,"x":7}`. This is the
+    /// default.
+    #[default]
+    Flat,
+    /// Every field at the top level, with span fields prefixed by the name
+    /// of their span: `{"message":"Test","z":10,"child.y":9,"parent.x":7}`.
+    PrefixedFlat,
+    /// Span fields grouped by span, root first, under `spans`:
+    /// `{"message":"Test","z":10,"spans":{"parent":{"x":7},"child":{"y":9}}}`.
+    NestedBySpan,
+    /// Event fields grouped under `fields`, and span fields at the top level:
+    /// `{"fields":{"message":"Test","z":10},"y":9,"x":7}`.
+    FieldsObject,
+    /// Event and span fields grouped under `fields`, keeping the message at
+    /// the top level: `{"message":"Test","fields":{"z":10,"y":9,"x":7}}`.
+    AllFieldsObject,
+}
+
+/// Where a field was recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FieldSource {
+    Event,
+    /// A span, and how many spans are between it and the event.
+    Span {
+        name: &'static str,
+        depth: usize,
+    },
+}
+
+impl FieldSource {
+    /// Prefix used when a field from this source has to be renamed.
+    fn prefix(&self) -> &'static str {
+        match self {
+            FieldSource::Event => "fields",
+            FieldSource::Span { name, .. } => name,
+        }
+    }
+}
+
+/// Which object a field is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Group {
+    Top,
+    Event,
+    Span { name: &'static str, depth: usize },
+}
+
+/// The value of a field.
+#[derive(Debug, Clone)]
+pub(crate) enum FieldValue {
+    Value(Value),
+    /// A value shared with a span's cached fields, along with its JSON.
+    Cached(Arc<CachedValue>),
+}
+
+#[derive(Debug)]
+pub(crate) struct CachedValue {
+    value: Value,
+    json: Box<RawValue>,
+}
+
+impl FieldValue {
+    /// Serialize `value` up front, so it can be written out as is for every
+    /// event. Falls back to a plain value if it can't be serialized.
+    pub(crate) fn cached(value: Value) -> Self {
+        match serde_json::value::to_raw_value(&value) {
+            Ok(json) => FieldValue::Cached(Arc::new(CachedValue { value, json })),
+            Err(_) => FieldValue::Value(value),
+        }
+    }
+
+    pub(crate) fn as_value(&self) -> &Value {
+        match self {
+            FieldValue::Value(value) => value,
+            FieldValue::Cached(cached) => &cached.value,
+        }
+    }
+
+    pub(crate) fn into_value(self) -> Value {
+        match self {
+            FieldValue::Value(value) => value,
+            FieldValue::Cached(cached) => match Arc::try_unwrap(cached) {
+                Ok(cached) => cached.value,
+                Err(cached) => cached.value.clone(),
+            },
+        }
+    }
+
+    /// The value's JSON, if it has already been serialized.
+    pub(crate) fn json(&self) -> Option<&RawValue> {
+        match self {
+            FieldValue::Value(_) => None,
+            FieldValue::Cached(cached) => Some(&cached.json),
+        }
+    }
+}
+
+impl From<Value> for FieldValue {
+    fn from(value: Value) -> Self {
+        FieldValue::Value(value)
+    }
+}
+
+#[derive(Debug)]
+struct Entry<'k> {
+    key: Cow<'k, str>,
+    value: FieldValue,
+    /// Set once `value` has been turned into an array by `KeepAll`.
+    collected: bool,
+    builtin: bool,
+    group: Group,
+}
+
+/// An ordered set of fields in which every key is unique.
+///
+/// Built-in keys (timestamp, level, ...) are reserved up front so that user
+/// fields can never displace them; a user field that reuses one is renamed
+/// to `<source>.<key>`, where the source is `fields` for the event itself.
+///
+/// Depending on the [`Layout`], some fields are grouped into a nested object
+/// instead. Keys only need to be unique within their object.
+#[derive(Debug)]
+pub(crate) struct FlatFields<'k> {
+    policy: CollisionPolicy,
+    layout: Layout,
+    /// The key of the object fields are grouped under, if the layout groups
+    /// them.
+    group_key: &'k str,
+    reserved: &'k HashSet<String>,
+    renames: Option<&'k HashMap<String, String>>,
+    /// Whether grouped fields are written as strings.
+    keyword_group: bool,
+    #[cfg(feature = "redact")]
+    redactor: Option<&'k Redactor>,
+    entries: Vec<Entry<'k>>,
+    /// A user field to keep the innermost value of, and that value.
+    watch: Option<&'k str>,
+    watched: Option<FieldValue>,
+    /// The index of the first entry with each key, by object.
+    positions: HashMap<Group, HashMap<Cow<'k, str>, usize>>,
+}
+
+impl<'k> FlatFields<'k> {
+    pub(crate) fn new(policy: CollisionPolicy, reserved: &'k HashSet<String>) -> Self {
+        Self {
+            policy,
+            layout: Layout::Flat,
+            group_key: "",
+            reserved,
+            renames: None,
+            keyword_group: false,
+            #[cfg(feature = "redact")]
+            redactor: None,
+            entries: Vec::new(),
+            watch: None,
+            watched: None,
+            positions: HashMap::new(),
+        }
+    }
+
+    /// Rename user fields as they are inserted, before resolving collisions.
+    pub(crate) fn with_renames(mut self, renames: &'k HashMap<String, String>) -> Self {
+        self.renames = Some(renames).filter(|renames| !renames.is_empty());
+        self
+    }
+
+    /// Lay out user fields with `layout`, grouping them under `group_key` if
+    /// it groups them.
+    pub(crate) fn with_layout(mut self, layout: Layout, group_key: &'k str) -> Self {
+        self.layout = layout;
+        self.group_key = group_key;
+        self
+    }
+
+    /// Write the fields grouped by the layout as strings, leaving out arrays,
+    /// objects and nulls, as ECS `labels` require.
+    pub(crate) fn with_keyword_group(mut self, keyword_group: bool) -> Self {
+        self.keyword_group = keyword_group;
+        self
+    }
+
+    /// Keep aside the innermost value of the user field `key`, as inserted,
+    /// to read the trace id from.
+    pub(crate) fn with_watch(mut self, key: Option<&'k str>) -> Self {
+        self.watch = key;
+        self
+    }
+
+    /// The value kept aside by [`with_watch`](Self::with_watch), if the field
+    /// was inserted.
+    pub(crate) fn watched(&self) -> Option<&FieldValue> {
+        self.watched.as_ref()
+    }
+
+    /// The keys user fields may not take at the top level.
+    pub(crate) fn reserved(&self) -> &'k HashSet<String> {
+        self.reserved
+    }
+
+    /// Add one of the built-in fields. These are never subject to the
+    /// collision policy.
+    pub(crate) fn push_builtin(&mut self, key: &'k str, value: impl Into<Value>) {
+        self.push_builtin_value(key, FieldValue::Value(value.into()));
+    }
+
+    pub(crate) fn push_builtin_value(&mut self, key: &'k str, value: FieldValue) {
+        self.push_entry(Cow::Borrowed(key), value, true, Group::Top);
+    }
+
+    /// Redact user fields as they are inserted, before renaming them.
+    #[cfg(feature = "redact")]
+    pub(crate) fn with_redactor(mut self, redactor: Option<&'k Redactor>) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Add a user field, resolving any collision with a field already added.
+    pub(crate) fn insert(
+        &mut self,
+        source: FieldSource,
+        key: impl Into<Cow<'k, str>>,
+        value: impl Into<FieldValue>,
+    ) {
+        let (key, value) = (key.into(), value.into());
+        #[cfg(feature = "redact")]
+        let value = match self.redactor {
+            Some(redactor) => match redactor.redact(&key, value) {
+                Some(value) => value,
+                None => return,
+            },
+            None => value,
+        };
+        self.insert_redacted(source, key, value);
+    }
+
+    /// Add a user field that has already been redacted.
+    pub(crate) fn insert_redacted(
+        &mut self,
+        source: FieldSource,
+        key: impl Into<Cow<'k, str>>,
+        value: FieldValue,
+    ) {
+        let key = key.into();
+        if self.watched.is_none() && self.watch == Some(key.as_ref()) {
+            self.watched = Some(value.clone());
+        }
+        // The message stays at the top level whatever it is renamed to.
+        let is_message = source == FieldSource::Event && key == "message";
+        let key = match self.renames.and_then(|renames| renames.get(key.as_ref())) {
+            Some(renamed) => Cow::Borrowed(renamed.as_str()),
+            None => key,
+        };
+
+        let (group, key) = match (self.layout, source) {
+            (Layout::PrefixedFlat, FieldSource::Span { name, .. }) => {
+                (Group::Top, Cow::Owned(format!("{}.{}", name, key)))
+            }
+            (Layout::NestedBySpan, FieldSource::Span { name, depth }) => {
+                (Group::Span { name, depth }, key)
+            }
+            (Layout::FieldsObject, FieldSource::Event) => (Group::Event, key),
+            (Layout::AllFieldsObject, _) if is_message => (Group::Top, key),
+            (Layout::AllFieldsObject, _) => (Group::Event, key),
+            _ => (Group::Top, key),
+        };
+        let value = match value.as_value() {
+            _ if !self.keyword_group || group != Group::Event => value,
+            Value::String(_) => value,
+            Value::Number(_) | Value::Bool(_) => Value::String(value.as_value().to_string()).into(),
+            Value::Null | Value::Array(_) | Value::Object(_) => return,
+        };
+
+        if group == Group::Top && self.reserved.contains(key.as_ref()) {
+            let key = self.unique_key(group, format!("{}.{}", source.prefix(), key));
+            return self.push(group, Cow::Owned(key), value);
+        }
+
+        let Some(existing) = self.position(group, &key) else {
+            return self.push(group, key, value);
+        };
+
+        match self.policy {
+            CollisionPolicy::InnermostWins => {}
+            CollisionPolicy::OutermostWins => self.entries[existing].value = value,
+            CollisionPolicy::KeepAll => {
+                let entry = &mut self.entries[existing];
+                let mut values = match std::mem::replace(&mut entry.value, Value::Null.into()) {
+                    FieldValue::Value(Value::Array(values)) if entry.collected => values,
+                    first => vec![first.into_value()],
+                };
+                values.push(value.into_value());
+                entry.value = Value::Array(values).into();
+                entry.collected = true;
+            }
+            CollisionPolicy::PrefixWithSpan => {
+                let key = self.unique_key(group, format!("{}.{}", source.prefix(), key));
+                self.push(group, Cow::Owned(key), value);
+            }
+        }
+    }
+
+    /// The built-in fields only, in the order they were added.
+    pub(crate) fn builtins(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.builtin)
+            .map(|entry| (entry.key.as_ref(), entry.value.as_value()))
+    }
+
+    /// The value of the first field named `key`, in whichever object it is.
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        self.find(key).map(|(_, value)| value)
+    }
+
+    /// The index and value of the first field named `key`, in whichever
+    /// object it is.
+    pub(crate) fn find(&self, key: &str) -> Option<(usize, &Value)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .find(|(_, entry)| entry.key == key)
+            .map(|(index, entry)| (index, entry.value.as_value()))
+    }
+
+    /// Something that serializes these fields as a map, nesting the groups
+    /// the layout calls for.
+    ///
+    /// Only set `raw_json` when serializing with `serde_json`: values that
+    /// were serialized when they were cached are then copied as they are.
+    pub(crate) fn serializable(&self, raw_json: bool) -> impl Serialize + '_ {
+        self.serializable_without(raw_json, &[])
+    }
+
+    /// Like [`serializable`](Self::serializable), but leaving out the
+    /// fields at the indices in `skip`.
+    pub(crate) fn serializable_without<'f>(
+        &'f self,
+        raw_json: bool,
+        skip: &'f [usize],
+    ) -> impl Serialize + 'f {
+        Render {
+            fields: self,
+            raw_json,
+            skip,
+        }
+    }
+
+    fn push(&mut self, group: Group, key: Cow<'k, str>, value: FieldValue) {
+        self.push_entry(key, value, false, group);
+    }
+
+    fn push_entry(&mut self, key: Cow<'k, str>, value: FieldValue, builtin: bool, group: Group) {
+        self.positions
+            .entry(group)
+            .or_default()
+            .entry(key.clone())
+            .or_insert(self.entries.len());
+        self.entries.push(Entry {
+            key,
+            value,
+            collected: false,
+            builtin,
+            group,
+        });
+    }
+
+    fn position(&self, group: Group, key: &str) -> Option<usize> {
+        self.positions.get(&group)?.get(key).copied()
+    }
+
+    fn is_taken(&self, group: Group, key: &str) -> bool {
+        (group == Group::Top && self.reserved.contains(key)) || self.position(group, key).is_some()
+    }
+
+    /// Returns `key`, or `key.N` for the first `N` that is not yet taken.
+    fn unique_key(&self, group: Group, key: String) -> String {
+        if !self.is_taken(group, &key) {
+            return key;
+        }
+        (2..)
+            .map(|n| format!("{}.{}", key, n))
+            .find(|candidate| !self.is_taken(group, candidate))
+            .expect("ran out of suffixes")
+    }
+}
+
+struct Render<'f, 'k> {
+    fields: &'f FlatFields<'k>,
+    raw_json: bool,
+    skip: &'f [usize],
+}
+
+impl Render<'_, '_> {
+    fn value<'v>(&self, value: &'v FieldValue) -> RenderValue<'v> {
+        RenderValue {
+            value,
+            raw_json: self.raw_json,
+        }
+    }
+
+    /// A map of the fields in `group`.
+    fn group(&self, group: Group) -> RenderGroup<'_, '_, '_> {
+        RenderGroup {
+            render: self,
+            group,
+        }
+    }
+}
+
+impl Serialize for Render<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        let mut wrote_group = false;
+        for (index, entry) in self.fields.entries.iter().enumerate() {
+            match entry.group {
+                Group::Top if self.skip.contains(&index) => {}
+                Group::Top => map.serialize_entry(&entry.key, &self.value(&entry.value))?,
+                // Each grouped object is written where its first field would be.
+                _ if wrote_group => {}
+                Group::Event => {
+                    map.serialize_entry(self.fields.group_key, &self.group(Group::Event))?;
+                    wrote_group = true;
+                }
+                Group::Span { .. } => {
+                    map.serialize_entry(self.fields.group_key, &RenderSpans(self))?;
+                    wrote_group = true;
+                }
+            }
+        }
+        map.end()
+    }
+}
+
+struct RenderGroup<'r, 'f, 'k> {
+    render: &'r Render<'f, 'k>,
+    group: Group,
+}
+
+impl Serialize for RenderGroup<'_, '_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (index, entry) in self.render.fields.entries.iter().enumerate() {
+            if entry.group == self.group && !self.render.skip.contains(&index) {
+                map.serialize_entry(&entry.key, &self.render.value(&entry.value))?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// A map from span name to the span's fields, root first. A span with the
+/// same name as one of its parents is written as `<name>.2`, and so on.
+struct RenderSpans<'r, 'f, 'k>(&'r Render<'f, 'k>);
+
+impl Serialize for RenderSpans<'_, '_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut spans: Vec<Group> = Vec::new();
+        for entry in &self.0.fields.entries {
+            if matches!(entry.group, Group::Span { .. }) && !spans.contains(&entry.group) {
+                spans.push(entry.group);
+            }
+        }
+        spans.sort_by_key(|group| match group {
+            Group::Span { depth, .. } => std::cmp::Reverse(*depth),
+            _ => std::cmp::Reverse(0),
+        });
+
+        let mut map = serializer.serialize_map(Some(spans.len()))?;
+        let mut names: Vec<&str> = Vec::new();
+        for group in spans {
+            let Group::Span { name, .. } = group else {
+                continue;
+            };
+            let seen = names.iter().filter(|seen| **seen == name).count();
+            names.push(name);
+            match seen {
+                0 => map.serialize_entry(name, &self.0.group(group))?,
+                n => map.serialize_entry(&format!("{}.{}", name, n + 1), &self.0.group(group))?,
+            }
+        }
+        map.end()
+    }
+}
+
+struct RenderValue<'v> {
+    value: &'v FieldValue,
+    raw_json: bool,
+}
+
+impl Serialize for RenderValue<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.value.json() {
+            Some(json) if self.raw_json => json.serialize(serializer),
+            _ => self.value.as_value().serialize(serializer),
+        }
+    }
+}
+
+/// `Visit` implementation that passes every field on to a closure as a
+/// `serde_json::Value`.
+///
+/// Values are converted the same way `tracing_serde::SerdeMapVisitor` does.
+pub(crate) struct FieldVisitor<F>(F);
+
+impl<F> FieldVisitor<F>
+where
+    F: FnMut(&'static str, Value),
+{
+    pub(crate) fn new(record: F) -> Self {
+        Self(record)
+    }
+}
+
+impl<F> Visit for FieldVisitor<F>
+where
+    F: FnMut(&'static str, Value),
+{
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        (self.0)(field.name(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        (self.0)(field.name(), format!("{:?}", value).into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        (self.0)(field.name(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        (self.0)(field.name(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        (self.0)(field.name(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        (self.0)(field.name(), value.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn span(name: &'static str, depth: usize) -> FieldSource {
+        FieldSource::Span { name, depth }
+    }
+
+    fn collect(policy: CollisionPolicy) -> Vec<(String, Value)> {
+        let reserved = HashSet::from(["level".to_string()]);
+        let mut fields = FlatFields::new(policy, &reserved);
+        fields.push_builtin("level", "INFO");
+        fields.insert(FieldSource::Event, "id", json!(1));
+        fields.insert(span("child", 0), "id", json!(2));
+        fields.insert(span("parent", 1), "id", json!(3));
+        fields.insert(span("parent", 1), "level", json!("high"));
+        fields
+            .entries
+            .iter()
+            .map(|entry| (entry.key.to_string(), entry.value.as_value().clone()))
+            .collect()
+    }
+
+    fn pairs(expected: Value) -> Vec<(String, Value)> {
+        expected
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|pair| (pair[0].as_str().unwrap().to_string(), pair[1].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn should_resolve_collisions_with_each_policy() {
+        assert_eq!(
+            collect(CollisionPolicy::InnermostWins),
+            pairs(json!([
+                ["level", "INFO"],
+                ["id", 1],
+                ["parent.level", "high"]
+            ])),
+        );
+        assert_eq!(
+            collect(CollisionPolicy::OutermostWins),
+            pairs(json!([
+                ["level", "INFO"],
+                ["id", 3],
+                ["parent.level", "high"]
+            ])),
+        );
+        assert_eq!(
+            collect(CollisionPolicy::KeepAll),
+            pairs(json!([
+                ["level", "INFO"],
+                ["id", [1, 2, 3]],
+                ["parent.level", "high"]
+            ])),
+        );
+        assert_eq!(
+            collect(CollisionPolicy::PrefixWithSpan),
+            pairs(json!([
+                ["level", "INFO"],
+                ["id", 1],
+                ["child.id", 2],
+                ["parent.id", 3],
+                ["parent.level", "high"],
+            ])),
+        );
+    }
+
+    #[test]
+    fn should_suffix_renamed_keys_that_are_still_taken() {
+        let reserved = HashSet::new();
+        let mut fields = FlatFields::new(CollisionPolicy::PrefixWithSpan, &reserved);
+        fields.insert(FieldSource::Event, "id", json!(1));
+        fields.insert(span("a", 0), "id", json!(2));
+        fields.insert(span("a", 1), "id", json!(3));
+
+        let keys: Vec<&str> = fields.entries.iter().map(|entry| &*entry.key).collect();
+        assert_eq!(keys, ["id", "a.id", "a.id.2"]);
+    }
+
+    #[test]
+    fn should_write_grouped_fields_as_keywords() {
+        let reserved = HashSet::new();
+        let mut fields = FlatFields::new(CollisionPolicy::default(), &reserved)
+            .with_layout(Layout::AllFieldsObject, "labels")
+            .with_keyword_group(true);
+        fields.insert(FieldSource::Event, "message", json!(1));
+        fields.insert(FieldSource::Event, "n", json!(2.5));
+        fields.insert(FieldSource::Event, "object", json!({ "a": 1 }));
+        fields.insert(span("a", 0), "array", json!([1]));
+        fields.insert(span("a", 0), "none", Value::Null);
+
+        let json = serde_json::to_value(fields.serializable(false)).unwrap();
+        assert_eq!(json, json!({ "message": 1, "labels": { "n": "2.5" } }));
+    }
+
+    #[test]
+    fn should_keep_a_renamed_message_at_the_top_level() {
+        let reserved = HashSet::new();
+        let renames = HashMap::from([("message".to_string(), "msg".to_string())]);
+        let mut fields = FlatFields::new(CollisionPolicy::default(), &reserved)
+            .with_renames(&renames)
+            .with_layout(Layout::AllFieldsObject, "fields");
+        fields.insert(FieldSource::Event, "message", json!("Hello"));
+        fields.insert(FieldSource::Event, "x", json!(1));
+
+        let json = serde_json::to_value(fields.serializable(false)).unwrap();
+        assert_eq!(json, json!({ "msg": "Hello", "fields": { "x": 1 } }));
+    }
+}
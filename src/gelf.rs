@@ -0,0 +1,633 @@
+//! GELF 1.1 for Graylog: a sibling of `SolinkJsonFormat`, and a `MakeWriter`
+//! that sends its messages over UDP or TCP.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::DateTime;
+use flate2::{write::ZlibEncoder, Compression};
+use serde_json::Value;
+use tracing::{Event, Metadata, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, MakeWriter},
+    registry::LookupSpan,
+};
+
+use crate::{
+    fields::FlatFields,
+    insert_formatted_fields,
+    sink::{udp_socket, LineFields},
+    syslog::severity,
+    write_str, Builtin, FormatError, SolinkJsonFormat, TimestampFormat,
+};
+
+/// `FormatEvent` for GELF 1.1, with the same fields as [`SolinkJsonFormat`]:
+///
+/// ```txt
+/// {"version":"1.1","host":"web-1","short_message":"Hello","timestamp":1718745464.902,"level":6,"_target":"app","_x":7}
+/// ```
+///
+/// The message is the `short_message`, or, if it has more than one line, its
+/// first line is, and the whole message is the `full_message`. Events without
+/// a message use their target instead. The level is a syslog severity number.
+/// If timestamps are on, the timestamp the format wrote is converted to
+/// seconds since the Unix epoch, unless it is a custom string that is not
+/// RFC 3339. The host is the `hostname` field if it is added, or the
+/// machine's host name otherwise.
+///
+/// Every other field, built-in, event or span, is an additional field, with
+/// its name prefixed by `_` and characters other than letters, digits, `_`,
+/// `.` and `-` replaced by `_`. As GELF reserves `_id`, a field named `id` is
+/// written as `_id_`. Names that are already taken get a `.2`, `.3`, ...
+/// suffix. Values other than strings and numbers are written as JSON
+/// strings.
+///
+/// Each message ends with a newline. Use [`GelfWriter`] to send them to
+/// Graylog over UDP or TCP.
+pub struct SolinkGelfFormat {
+    format: SolinkJsonFormat,
+    host: String,
+}
+
+impl SolinkGelfFormat {
+    pub fn new() -> Self {
+        Self {
+            format: SolinkJsonFormat::new(),
+            host: gethostname::gethostname().to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Set the options fields are collected with.
+    pub fn with_format(mut self, format: SolinkJsonFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set the host, if the format does not add the `hostname` field. The
+    /// machine's host name by default.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    fn encode(
+        &self,
+        meta: &Metadata,
+        fields: &FlatFields,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), FormatError> {
+        let keys = &self.format.keys;
+        let [timestamp, level_value, host, message_entry] = [
+            keys.get(Builtin::Timestamp),
+            keys.get(Builtin::Level),
+            keys.get(Builtin::Hostname),
+            self.format.message_key(),
+        ]
+        .map(|key| fields.find(key));
+
+        let message = match message_entry {
+            Some((_, Value::String(message))) => message.clone(),
+            Some((_, message)) => message.to_string(),
+            None => String::new(),
+        };
+        let (short_message, full_message) = match message.split_once('\n') {
+            Some((first_line, _)) => (first_line.to_string(), Some(message.clone())),
+            None => (message, None),
+        };
+        let short_message = match short_message.is_empty() {
+            true => meta.target().to_string(),
+            false => short_message,
+        };
+        let mut gelf: Vec<(String, Value)> = vec![
+            ("version".to_string(), "1.1".into()),
+            (
+                "host".to_string(),
+                match host {
+                    Some((_, host)) => host.clone(),
+                    None => self.host.as_str().into(),
+                },
+            ),
+            ("short_message".to_string(), short_message.into()),
+        ];
+        if let Some(full_message) = full_message {
+            gelf.push(("full_message".to_string(), full_message.into()));
+        }
+        if let Some(seconds) = timestamp.and_then(|(_, timestamp)| self.seconds(timestamp)) {
+            gelf.push(("timestamp".to_string(), seconds.into()));
+        }
+        gelf.push(("level".to_string(), severity(meta.level()).into()));
+
+        // Flatten the other fields as they would be written as JSON.
+        let skip: Vec<usize> = [timestamp, level_value, host, message_entry]
+            .into_iter()
+            .flatten()
+            .map(|(index, _)| index)
+            .collect();
+        let json = serde_json::to_vec(&fields.serializable_without(true, &skip))
+            .map_err(FormatError::Serialize)?;
+        let others = LineFields::parse(&json).map_or_else(Vec::new, |fields| fields.0);
+        let mut names = HashSet::new();
+        for (key, value) in others {
+            let value = match value {
+                Value::Null => continue,
+                Value::Number(_) | Value::String(_) => value,
+                value => value.to_string().into(),
+            };
+            // Different keys can have the same name once sanitized.
+            let name = additional_field_name(&key);
+            let name = match names.contains(&name) {
+                true => (2..)
+                    .map(|n| format!("{}.{}", name, n))
+                    .find(|candidate| !names.contains(candidate))
+                    .expect("ran out of suffixes"),
+                false => name,
+            };
+            names.insert(name.clone());
+            gelf.push((name, value));
+        }
+
+        buffer.push(b'{');
+        for (index, (key, value)) in gelf.iter().enumerate() {
+            if index > 0 {
+                buffer.push(b',');
+            }
+            serde_json::to_writer(&mut *buffer, key).map_err(FormatError::Serialize)?;
+            buffer.push(b':');
+            serde_json::to_writer(&mut *buffer, value).map_err(FormatError::Serialize)?;
+        }
+        buffer.extend_from_slice(b"}\n");
+        Ok(())
+    }
+
+    /// The timestamp the format wrote, in seconds since the Unix epoch, to
+    /// the millisecond.
+    fn seconds(&self, timestamp: &Value) -> Option<f64> {
+        let millis = match self.format.timestamp_format {
+            TimestampFormat::UnixSeconds => timestamp.as_i64()?.checked_mul(1000)?,
+            TimestampFormat::UnixMillis => timestamp.as_i64()?,
+            TimestampFormat::UnixMicros => timestamp.as_i64()? / 1000,
+            TimestampFormat::UnixNanos => timestamp.as_i64()? / 1_000_000,
+            _ => DateTime::parse_from_rfc3339(timestamp.as_str()?)
+                .ok()?
+                .timestamp_millis(),
+        };
+        Some(millis as f64 / 1000.0)
+    }
+}
+
+/// `_` followed by `key`, with characters GELF does not allow replaced.
+fn additional_field_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') => c,
+            _ => '_',
+        })
+        .collect();
+    match name.as_str() {
+        "id" => "_id_".to_string(),
+        _ => format!("_{}", name),
+    }
+}
+
+impl Default for SolinkGelfFormat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<SolinkJsonFormat> for SolinkGelfFormat {
+    fn from(format: SolinkJsonFormat) -> Self {
+        Self::new().with_format(format)
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for SolinkGelfFormat
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        self.format.write_event(
+            event,
+            ctx.event_scope(),
+            insert_formatted_fields::<N, S>,
+            |fields, buffer| self.encode(event.metadata(), fields, buffer),
+            |line| write_str(&mut writer, line),
+        )
+    }
+}
+
+/// `MakeWriter` that sends each message written by [`SolinkGelfFormat`] to
+/// Graylog:
+///
+/// ```rs
+///     tracing_subscriber::fmt()
+///         .fmt_fields(JsonFields::new())
+///         .event_format(SolinkGelfFormat::new())
+///         .with_writer(GelfWriter::udp("graylog:12201")?.with_compression(true))
+///         .init();
+/// ```
+///
+/// Over UDP, each message is a datagram, split into chunks if it is larger
+/// than the chunk size, and optionally compressed with zlib. Messages that
+/// would need more than 128 chunks are dropped. Over TCP, messages are
+/// separated by null bytes, and are never compressed, as GELF does not allow
+/// it. Connecting and writing give up after a timeout, and after a failure,
+/// messages are dropped without trying to reconnect for a backoff that
+/// doubles from 100ms up to 10s, so logging never blocks for long while
+/// Graylog is down.
+pub struct GelfWriter {
+    transport: Transport,
+    compress: bool,
+    chunk_size: usize,
+    timeout: Duration,
+    message_ids: AtomicU64,
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp {
+        addr: SocketAddr,
+        connection: Mutex<Connection>,
+    },
+}
+
+/// A TCP stream, connected on first use, and again after an error once the
+/// backoff has passed.
+struct Connection {
+    stream: Option<TcpStream>,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The most chunks a message can be split into.
+const MAX_CHUNKS: usize = 128;
+/// The magic bytes, message id, sequence number and sequence count.
+const CHUNK_HEADER_LEN: usize = 12;
+
+impl GelfWriter {
+    /// Send over UDP to `addr`, such as `"graylog:12201"`.
+    pub fn udp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self::new(Transport::Udp(udp_socket(addr)?)))
+    }
+
+    /// Send over TCP to `addr`, connecting when the first message is sent.
+    pub fn tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+        })?;
+        Ok(Self::new(Transport::Tcp {
+            addr,
+            connection: Mutex::new(Connection {
+                stream: None,
+                retry_at: None,
+                backoff: INITIAL_BACKOFF,
+            }),
+        }))
+    }
+
+    fn new(transport: Transport) -> Self {
+        let seed = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        Self {
+            transport,
+            compress: false,
+            chunk_size: 1420,
+            timeout: Duration::from_secs(1),
+            message_ids: AtomicU64::new(seed as u64 ^ u64::from(std::process::id()) << 32),
+        }
+    }
+
+    /// Set whether to compress UDP messages with zlib. Off by default.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Set the largest datagram sent over UDP, with messages larger than this
+    /// split into chunks. 1420 bytes by default, to fit in one packet on most
+    /// networks.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(CHUNK_HEADER_LEN + 1);
+        self
+    }
+
+    /// Set how long connecting and writing over TCP can take before the
+    /// message is dropped. 1s by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        let message = message.strip_suffix(b"\n").unwrap_or(message);
+        match &self.transport {
+            Transport::Udp(socket) => {
+                let compressed;
+                let message = match self.compress {
+                    true => {
+                        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                        encoder.write_all(message)?;
+                        compressed = encoder.finish()?;
+                        &compressed
+                    }
+                    false => message,
+                };
+                for datagram in self.chunks(message)? {
+                    socket.send(&datagram)?;
+                }
+                Ok(())
+            }
+            Transport::Tcp { addr, connection } => {
+                let mut connection = connection.lock().unwrap_or_else(|error| error.into_inner());
+                let mut framed = message.to_vec();
+                framed.push(0);
+                let result = match connection.stream.take() {
+                    Some(stream) => Ok(stream),
+                    None => self.connect(addr, &connection),
+                }
+                .and_then(|stream| {
+                    (&stream).write_all(&framed)?;
+                    Ok(stream)
+                });
+                match result {
+                    Ok(stream) => {
+                        connection.stream = Some(stream);
+                        connection.retry_at = None;
+                        connection.backoff = INITIAL_BACKOFF;
+                        Ok(())
+                    }
+                    // The stream is dropped, to reconnect after the backoff.
+                    Err(error) => {
+                        if connection
+                            .retry_at
+                            .filter(|at| *at > Instant::now())
+                            .is_none()
+                        {
+                            connection.retry_at = Some(Instant::now() + connection.backoff);
+                            connection.backoff = (connection.backoff * 2).min(MAX_BACKOFF);
+                        }
+                        Err(error)
+                    }
+                }
+            }
+        }
+    }
+
+    fn connect(&self, addr: &SocketAddr, connection: &Connection) -> io::Result<TcpStream> {
+        if let Some(retry_at) = connection.retry_at.filter(|at| *at > Instant::now()) {
+            let wait = retry_at - Instant::now();
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("not reconnecting for another {}ms", wait.as_millis()),
+            ));
+        }
+        let stream = TcpStream::connect_timeout(addr, self.timeout)?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(stream)
+    }
+
+    /// Split `message` into datagrams of at most the chunk size.
+    fn chunks(&self, message: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        if message.len() <= self.chunk_size {
+            return Ok(vec![message.to_vec()]);
+        }
+        let chunks: Vec<&[u8]> = message.chunks(self.chunk_size - CHUNK_HEADER_LEN).collect();
+        if chunks.len() > MAX_CHUNKS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "message of {} bytes needs more than {} chunks",
+                    message.len(),
+                    MAX_CHUNKS
+                ),
+            ));
+        }
+
+        let id = self
+            .message_ids
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        let datagrams = chunks
+            .iter()
+            .enumerate()
+            .map(|(sequence, chunk)| {
+                let mut datagram = Vec::with_capacity(CHUNK_HEADER_LEN + chunk.len());
+                datagram.extend_from_slice(&[0x1e, 0x0f]);
+                datagram.extend_from_slice(&id);
+                datagram.extend_from_slice(&[sequence as u8, chunks.len() as u8]);
+                datagram.extend_from_slice(chunk);
+                datagram
+            })
+            .collect();
+        Ok(datagrams)
+    }
+}
+
+impl<'a> MakeWriter<'a> for GelfWriter {
+    type Writer = GelfMessage<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        GelfMessage {
+            writer: self,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Buffers the message written for one event, and sends it when dropped.
+pub struct GelfMessage<'a> {
+    writer: &'a GelfWriter,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for GelfMessage<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for GelfMessage<'_> {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        if let Err(error) = self.writer.send(&self.buffer) {
+            eprintln!("failed to send log to Graylog: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener};
+
+    use flate2::read::ZlibDecoder;
+    use tracing::{dispatcher, error, info};
+    use tracing_subscriber::{fmt::format::JsonFields, layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::Clock;
+
+    fn log(writer: impl for<'a> MakeWriter<'a> + Send + Sync + 'static, f: impl FnOnce()) {
+        let format = SolinkJsonFormat::new()
+            .with_clock(Clock::fixed("2024-06-18T21:17:44.902Z".parse().unwrap()))
+            .with_target(false);
+        let layer = tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields::new())
+            .event_format(SolinkGelfFormat::from(format).with_host("web-1"))
+            .with_writer(writer);
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, f);
+    }
+
+    #[test]
+    fn should_send_chunked_zlib_messages_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let writer = GelfWriter::udp(server.local_addr().unwrap())
+            .unwrap()
+            .with_compression(true)
+            .with_chunk_size(40);
+        log(writer, || {
+            let span = tracing::info_span!("handle", id = 7, user.name = "bob");
+            let _s = span.enter();
+            error!(ok = false, "Failed\nat line 1")
+        });
+
+        // Reassemble the chunks, which arrive in order over loopback.
+        let mut datagram = [0; 64];
+        let mut compressed = Vec::new();
+        loop {
+            let len = server.recv(&mut datagram).unwrap();
+            assert_eq!(datagram[..2], [0x1e, 0x0f]);
+            compressed.extend_from_slice(&datagram[12..len]);
+            if datagram[10] + 1 == datagram[11] {
+                break;
+            }
+        }
+        let mut message = String::new();
+        ZlibDecoder::new(&compressed[..])
+            .read_to_string(&mut message)
+            .unwrap();
+        assert_eq!(
+            message,
+            concat!(
+                r#"{"version":"1.1","host":"web-1","short_message":"Failed","#,
+                r#""full_message":"Failed\nat line 1","timestamp":1718745464.902,"level":3,"#,
+                r#""_ok":"false","_span":"handle","_id_":7,"_user.name":"bob"}"#,
+            ),
+        );
+    }
+
+    #[test]
+    fn should_send_null_delimited_messages_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let writer = GelfWriter::tcp(listener.local_addr().unwrap()).unwrap();
+        log(writer, || {
+            info!(x = 7, "First");
+            info!(message = "Second");
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        let messages: Vec<&str> = std::str::from_utf8(&received)
+            .unwrap()
+            .split_terminator('\0')
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                concat!(
+                    r#"{"version":"1.1","host":"web-1","short_message":"First","#,
+                    r#""timestamp":1718745464.902,"level":6,"_x":7}"#,
+                ),
+                concat!(
+                    r#"{"version":"1.1","host":"web-1","short_message":"Second","#,
+                    r#""timestamp":1718745464.902,"level":6}"#,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn should_use_the_target_and_the_written_timestamp() {
+        let writer = crate::test_support::TestWriter::new();
+        let format = SolinkJsonFormat::new()
+            .with_clock(Clock::fixed("2024-06-18T21:17:44.902Z".parse().unwrap()))
+            .with_timestamp_format(TimestampFormat::Rfc3339(chrono::SecondsFormat::Secs))
+            .with_target(false);
+        let layer = tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields::new())
+            .event_format(SolinkGelfFormat::from(format).with_host("web-1"))
+            .with_writer(writer.clone());
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, || info!(target: "orders", x = 7));
+
+        assert_eq!(
+            writer.contents(),
+            concat!(
+                r#"{"version":"1.1","host":"web-1","short_message":"orders","#,
+                r#""timestamp":1718745464.0,"level":6,"_x":7}"#,
+                "\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn should_back_off_from_reconnecting_over_tcp() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let writer = GelfWriter::tcp(addr).unwrap();
+
+        let refused = writer.send(b"{}\n").unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+        let waiting = writer.send(b"{}\n").unwrap_err();
+        assert_eq!(waiting.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn should_suffix_additional_fields_with_the_same_name() {
+        let writer = crate::test_support::TestWriter::new();
+        log(writer.clone(), || {
+            info!(id_ = 3, id = 4, "a b" = 5, a_b = 6, "x")
+        });
+
+        assert_eq!(
+            writer.contents(),
+            concat!(
+                r#"{"version":"1.1","host":"web-1","short_message":"x","#,
+                r#""timestamp":1718745464.902,"level":6,"#,
+                r#""_id_":3,"_id_.2":4,"_a_b":5,"_a_b.2":6}"#,
+                "\n",
+            ),
+        );
+    }
+}
@@ -0,0 +1,651 @@
+//! Shipping lines in batches to Loki, Elasticsearch or any endpoint that
+//! accepts NDJSON, from a background thread.
+
+use std::{
+    fmt, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use serde_json::{json, Value};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::Clock;
+
+/// Options for shipping lines over HTTP in batches:
+///
+/// ```rs
+///     let (writer, _guard) = HttpShipper::loki("http://loki:3100")
+///         .with_label("service_name", "orders")
+///         .with_max_batch_events(500)
+///         .with_flush_interval(Duration::from_secs(2))
+///         .spawn()?;
+///     let log_to_loki = FlatJsonLayer::new().with_writer(writer);
+/// ```
+///
+/// Lines are queued, and sent from a background thread once a batch is full
+/// or the flush interval has passed since its first line. Failed requests
+/// are retried with exponential backoff when the error could be temporary:
+/// a connection error, `429 Too Many Requests` or a `5xx` status. When the
+/// queue is full, new lines are dropped rather than blocking the program.
+///
+/// Bodies are gzipped unless [`with_gzip(false)`](Self::with_gzip) is set.
+#[derive(Debug, Clone)]
+pub struct HttpShipper {
+    url: String,
+    api: Api,
+    headers: Vec<(String, String)>,
+    gzip: bool,
+    queue_capacity: usize,
+    max_batch_events: usize,
+    max_batch_bytes: usize,
+    flush_interval: Duration,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    timeout: Duration,
+    clock: Clock,
+}
+
+/// How a batch is sent.
+#[derive(Debug, Clone)]
+enum Api {
+    /// The lines, as they are.
+    Ndjson,
+    /// A Loki push request, with every line in one stream.
+    Loki { labels: Vec<(String, String)> },
+    /// An Elasticsearch bulk request creating a document for each line.
+    Elasticsearch { index: String },
+}
+
+impl HttpShipper {
+    /// POST the lines, one per line, to `url`.
+    pub fn ndjson(url: impl Into<String>) -> Self {
+        Self::new(url.into(), Api::Ndjson)
+    }
+
+    /// Push the lines to the Loki server at `base_url`, such as
+    /// `http://loki:3100`, in a single stream.
+    ///
+    /// The stream is labelled with [`with_label`](Self::with_label), or with
+    /// `service_name` set to the name of the executable if none are set.
+    pub fn loki(base_url: &str) -> Self {
+        let url = format!("{}/loki/api/v1/push", base_url.trim_end_matches('/'));
+        Self::new(url, Api::Loki { labels: Vec::new() })
+    }
+
+    /// Index each line as a document in `index`, or the data stream of that
+    /// name, with the bulk API of the Elasticsearch cluster at `base_url`.
+    ///
+    /// A bulk request that is accepted but fails for some documents is not
+    /// retried.
+    pub fn elasticsearch(base_url: &str, index: impl Into<String>) -> Self {
+        let url = format!("{}/_bulk", base_url.trim_end_matches('/'));
+        let index = index.into();
+        Self::new(url, Api::Elasticsearch { index })
+    }
+
+    fn new(url: String, api: Api) -> Self {
+        Self {
+            url,
+            api,
+            headers: Vec::new(),
+            gzip: true,
+            queue_capacity: 10_000,
+            max_batch_events: 1_000,
+            max_batch_bytes: 1024 * 1024,
+            flush_interval: Duration::from_secs(1),
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+            clock: Clock::default(),
+        }
+    }
+
+    /// Add a label to the Loki stream.
+    pub fn with_label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        if let Api::Loki { labels } = &mut self.api {
+            labels.push((name.into(), value.into()));
+        }
+        self
+    }
+
+    /// Add a header to every request, such as `Authorization`.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set whether to gzip request bodies. On by default.
+    pub fn with_gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// Set how many lines can be waiting to be sent before new lines are
+    /// dropped. 10,000 by default.
+    pub fn with_queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    /// Send a batch once it has this many lines. 1,000 by default.
+    pub fn with_max_batch_events(mut self, max_batch_events: usize) -> Self {
+        self.max_batch_events = max_batch_events;
+        self
+    }
+
+    /// Send a batch once its lines add up to this many bytes, before any
+    /// gzip compression. 1 MiB by default.
+    pub fn with_max_batch_bytes(mut self, max_batch_bytes: usize) -> Self {
+        self.max_batch_bytes = max_batch_bytes;
+        self
+    }
+
+    /// Send a batch once this long has passed since its first line, even if
+    /// it is not full. One second by default.
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Set how many times a batch is retried, waiting `initial_backoff`
+    /// before the first retry and twice as long before each of the next,
+    /// up to `max_backoff`. By default, 5 retries from 100ms up to 10s.
+    pub fn with_retries(
+        mut self,
+        max_retries: u32,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+    ) -> Self {
+        self.max_retries = max_retries;
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Set the timeout of each request. 10 seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the clock used to timestamp lines sent to Loki.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Start the background thread, returning the `MakeWriter` that queues
+    /// lines for it, and a guard that sends the lines still queued and stops
+    /// the thread when dropped.
+    pub fn spawn(self) -> io::Result<(HttpWriter, HttpGuard)> {
+        let (sender, receiver) = mpsc::sync_channel(self.queue_capacity);
+        let clock = self.clock.clone();
+        let agent = ureq::AgentBuilder::new().timeout(self.timeout).build();
+        let thread = std::thread::Builder::new()
+            .name("log-http-shipper".to_string())
+            .spawn(move || {
+                Worker {
+                    shipper: self,
+                    agent,
+                }
+                .run(receiver)
+            })?;
+
+        let writer = HttpWriter {
+            sender: sender.clone(),
+            dropped: Arc::default(),
+            clock,
+        };
+        let guard = HttpGuard {
+            sender,
+            thread: Some(thread),
+        };
+        Ok((writer, guard))
+    }
+}
+
+enum Message {
+    /// A line, and when it was written, in nanoseconds since the Unix epoch.
+    Line(Vec<u8>, i64),
+    Shutdown,
+}
+
+/// `MakeWriter` that queues each line to be shipped. Created with
+/// [`HttpShipper::spawn`].
+#[derive(Clone)]
+pub struct HttpWriter {
+    sender: SyncSender<Message>,
+    dropped: Arc<AtomicU64>,
+    clock: Clock,
+}
+
+impl HttpWriter {
+    /// How many lines were dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for HttpWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpWriter")
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+impl<'a> MakeWriter<'a> for HttpWriter {
+    type Writer = HttpLine<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        HttpLine {
+            writer: self,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Buffers the line written for one event, and queues it when dropped.
+pub struct HttpLine<'a> {
+    writer: &'a HttpWriter,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for HttpLine<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for HttpLine<'_> {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let line = std::mem::take(&mut self.buffer);
+        let time = self.writer.clock.now().timestamp_nanos_opt();
+        let time = time.unwrap_or_default();
+        let message = Message::Line(line, time);
+        if self.writer.sender.try_send(message).is_err() {
+            self.writer.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sends the lines still queued, and stops the background thread, when
+/// dropped.
+pub struct HttpGuard {
+    sender: SyncSender<Message>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for HttpGuard {
+    fn drop(&mut self) {
+        // Waits for room in the queue, so no line queued before is lost.
+        let _ = self.sender.send(Message::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Worker {
+    shipper: HttpShipper,
+    agent: ureq::Agent,
+}
+
+/// Lines waiting to be sent, with the time each was queued.
+#[derive(Default)]
+struct Batch {
+    lines: Vec<(Vec<u8>, i64)>,
+    bytes: usize,
+    deadline: Option<Instant>,
+}
+
+impl Worker {
+    fn run(self, receiver: Receiver<Message>) {
+        let mut batch = Batch::default();
+        loop {
+            let message = match batch.deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    receiver.recv_timeout(timeout)
+                }
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match message {
+                Ok(Message::Line(line, time)) => {
+                    batch.bytes += line.len();
+                    batch.lines.push((line, time));
+                    batch
+                        .deadline
+                        .get_or_insert_with(|| Instant::now() + self.shipper.flush_interval);
+                    if batch.lines.len() >= self.shipper.max_batch_events
+                        || batch.bytes >= self.shipper.max_batch_bytes
+                    {
+                        self.send(std::mem::take(&mut batch));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => self.send(std::mem::take(&mut batch)),
+                Ok(Message::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                    self.send(batch);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Send a batch, retrying it if the error could be temporary.
+    fn send(&self, batch: Batch) {
+        if batch.lines.is_empty() {
+            return;
+        }
+        let body = match self.body(&batch) {
+            Ok(body) => body,
+            Err(error) => {
+                eprintln!("failed to encode logs for {}: {}", self.shipper.url, error);
+                return;
+            }
+        };
+
+        let mut backoff = self.shipper.initial_backoff;
+        for attempt in 0.. {
+            let error = match self.request().send_bytes(&body) {
+                Ok(_) => return,
+                Err(ureq::Error::Status(status, _)) if status != 429 && status < 500 => {
+                    eprintln!("failed to ship logs to {}: {}", self.shipper.url, status);
+                    return;
+                }
+                Err(error) => error,
+            };
+            if attempt >= self.shipper.max_retries {
+                eprintln!("failed to ship logs to {}: {}", self.shipper.url, error);
+                return;
+            }
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(self.shipper.max_backoff);
+        }
+    }
+
+    fn request(&self) -> ureq::Request {
+        let content_type = match self.shipper.api {
+            Api::Ndjson | Api::Elasticsearch { .. } => "application/x-ndjson",
+            Api::Loki { .. } => "application/json",
+        };
+        let mut request = self
+            .agent
+            .post(&self.shipper.url)
+            .set("Content-Type", content_type);
+        if self.shipper.gzip {
+            request = request.set("Content-Encoding", "gzip");
+        }
+        for (name, value) in &self.shipper.headers {
+            request = request.set(name, value);
+        }
+        request
+    }
+
+    fn body(&self, batch: &Batch) -> io::Result<Vec<u8>> {
+        let lines = batch
+            .lines
+            .iter()
+            .map(|(line, time)| (line.strip_suffix(b"\n").unwrap_or(line), *time));
+
+        let mut body = Vec::with_capacity(batch.bytes);
+        match &self.shipper.api {
+            Api::Ndjson => {
+                for (line, _) in lines {
+                    body.extend_from_slice(line);
+                    body.push(b'\n');
+                }
+            }
+            Api::Elasticsearch { index } => {
+                let action = json!({ "create": { "_index": index } }).to_string();
+                for (line, _) in lines {
+                    body.extend_from_slice(action.as_bytes());
+                    body.push(b'\n');
+                    body.extend_from_slice(line);
+                    body.push(b'\n');
+                }
+            }
+            Api::Loki { labels } => {
+                let labels: serde_json::Map<String, Value> = match labels.is_empty() {
+                    true => [(
+                        "service_name".to_string(),
+                        crate::init::file_prefix().into(),
+                    )]
+                    .into_iter()
+                    .collect(),
+                    false => labels
+                        .iter()
+                        .map(|(name, value)| (name.clone(), value.clone().into()))
+                        .collect(),
+                };
+                let values: Vec<Value> = lines
+                    .map(|(line, time)| json!([time.to_string(), String::from_utf8_lossy(line)]))
+                    .collect();
+                let push = json!({ "streams": [{ "stream": labels, "values": values }] });
+                serde_json::to_writer(&mut body, &push)?;
+            }
+        }
+
+        match self.shipper.gzip {
+            true => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                io::Write::write_all(&mut encoder, &body)?;
+                encoder.finish()
+            }
+            false => Ok(body),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::Mutex,
+    };
+
+    use flate2::read::GzDecoder;
+    use tracing::{dispatcher, info};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{FlatJsonLayer, SolinkJsonFormat};
+
+    /// A request received by the mock server.
+    #[derive(Debug)]
+    struct Request {
+        path: String,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> Option<&str> {
+            let (_, value) = self.headers.iter().find(|(n, _)| n == name)?;
+            Some(value)
+        }
+    }
+
+    /// Answer each request with the next status, keeping the requests.
+    fn mock_server(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split(' ').nth(1).unwrap().to_string();
+
+                let mut headers = Vec::new();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((name, value)) => {
+                            headers.push((name.to_ascii_lowercase(), value.to_string()))
+                        }
+                        None => break,
+                    }
+                }
+                let (_, len) = headers.iter().find(|(n, _)| n == "content-length").unwrap();
+                let mut body = vec![0; len.parse().unwrap()];
+                reader.read_exact(&mut body).unwrap();
+                let body = match headers
+                    .iter()
+                    .any(|(n, v)| n == "content-encoding" && v == "gzip")
+                {
+                    true => {
+                        let mut text = String::new();
+                        GzDecoder::new(&body[..]).read_to_string(&mut text).unwrap();
+                        text
+                    }
+                    false => String::from_utf8(body).unwrap(),
+                };
+                received.lock().unwrap().push(Request {
+                    path,
+                    headers,
+                    body,
+                });
+
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn log(shipper: HttpShipper, events: usize) {
+        let (writer, guard) = shipper.spawn().unwrap();
+        let layer = FlatJsonLayer::new()
+            .with_format(
+                SolinkJsonFormat::new()
+                    .with_timestamp(false)
+                    .with_target(false),
+            )
+            .with_writer(writer);
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, || {
+            for n in 0..events {
+                info!(n, "Event")
+            }
+        });
+        drop(guard);
+    }
+
+    #[test]
+    fn should_send_gzipped_ndjson_in_batches() {
+        let (url, requests) = mock_server(vec![200, 200]);
+        log(
+            HttpShipper::ndjson(format!("{}/ingest", url))
+                .with_header("Authorization", "Bearer token")
+                .with_max_batch_events(2),
+            3,
+        );
+
+        let requests = requests.lock().unwrap();
+        let bodies: Vec<&str> = requests
+            .iter()
+            .map(|request| request.body.as_str())
+            .collect();
+        assert_eq!(
+            bodies,
+            [
+                concat!(
+                    r#"{"level":"INFO","message":"Event","n":0}"#,
+                    "\n",
+                    r#"{"level":"INFO","message":"Event","n":1}"#,
+                    "\n"
+                ),
+                concat!(r#"{"level":"INFO","message":"Event","n":2}"#, "\n"),
+            ],
+        );
+        assert_eq!(requests[0].path, "/ingest");
+        assert_eq!(
+            requests[0].header("content-type"),
+            Some("application/x-ndjson")
+        );
+        assert_eq!(requests[0].header("authorization"), Some("Bearer token"));
+    }
+
+    #[test]
+    fn should_retry_loki_pushes() {
+        let (url, requests) = mock_server(vec![503, 204]);
+        log(
+            HttpShipper::loki(&url)
+                .with_label("service_name", "orders")
+                .with_clock(Clock::fixed("2024-06-18T21:17:44.902137Z".parse().unwrap()))
+                .with_retries(3, Duration::from_millis(1), Duration::from_millis(1)),
+            2,
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, requests[1].body);
+        assert_eq!(requests[1].path, "/loki/api/v1/push");
+
+        let push: Value = serde_json::from_str(&requests[1].body).unwrap();
+        let stream = &push["streams"][0];
+        assert_eq!(stream["stream"], json!({ "service_name": "orders" }));
+        let lines: Vec<&Value> = stream["values"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|value| &value[1])
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"level":"INFO","message":"Event","n":0}"#,
+                r#"{"level":"INFO","message":"Event","n":1}"#,
+            ],
+        );
+        assert_eq!(stream["values"][0][0], "1718745464902137000");
+    }
+
+    #[test]
+    fn should_send_elasticsearch_bulk_requests() {
+        let (url, requests) = mock_server(vec![200]);
+        log(
+            HttpShipper::elasticsearch(&url, "logs-app").with_gzip(false),
+            1,
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/_bulk");
+        assert_eq!(requests[0].header("content-encoding"), None);
+        assert_eq!(
+            requests[0].body,
+            concat!(
+                r#"{"create":{"_index":"logs-app"}}"#,
+                "\n",
+                r#"{"level":"INFO","message":"Event","n":0}"#,
+                "\n"
+            ),
+        );
+    }
+}
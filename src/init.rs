@@ -0,0 +1,196 @@
+//! One call to set up logging: the format, a filter, where logs are written,
+//! and the global subscriber.
+
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{self, RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::writer::{BoxMakeWriter, MakeWriterExt},
+    layer::SubscriberExt,
+    util::{SubscriberInitExt, TryInitError},
+    EnvFilter,
+};
+
+use crate::{FlatJsonLayer, RotatingFile, RotatingFileWriter, SolinkJsonFormat};
+
+/// Start setting up logging with a [`FlatJsonLayer`]:
+///
+/// ```rs
+///     let _guards = solink_tracing_flat_json::init()
+///         .with_env_filter("RUST_LOG")
+///         .to_stdout()
+///         .to_rolling_file("/var/log/app", Rotation::DAILY)
+///         .install()?;
+/// ```
+///
+/// Every output is written to from a background thread. Keep the returned
+/// guards alive until the program exits, so that logs still buffered are
+/// flushed.
+pub fn init() -> Init {
+    Init::default()
+}
+
+/// Builder returned by [`init`].
+#[derive(Default)]
+pub struct Init {
+    format: SolinkJsonFormat,
+    filter_var: Option<String>,
+    stdout: bool,
+    files: Vec<(PathBuf, Rotation)>,
+    rotating_files: Vec<RotatingFile>,
+}
+
+impl Init {
+    /// Set the format each event is written in.
+    pub fn with_format(mut self, format: SolinkJsonFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Filter events with the directives in the environment variable `var`,
+    /// as with `RUST_LOG`. If it is not set, or can't be parsed, events at
+    /// `INFO` and above are written.
+    pub fn with_env_filter(mut self, var: impl Into<String>) -> Self {
+        self.filter_var = Some(var.into());
+        self
+    }
+
+    /// Write events to stdout. This is the default if no other output is
+    /// chosen.
+    pub fn to_stdout(mut self) -> Self {
+        self.stdout = true;
+        self
+    }
+
+    /// Write events to files in `dir`, starting a new file as often as
+    /// `rotation` says. Files are named after the executable, such as
+    /// `app.2024-06-18.log` with daily rotation.
+    pub fn to_rolling_file(mut self, dir: impl AsRef<Path>, rotation: Rotation) -> Self {
+        self.files.push((dir.as_ref().to_path_buf(), rotation));
+        self
+    }
+
+    /// Write events to a file that is rotated by size or time, and whose
+    /// old files are compressed and cleaned up, as set on `file`.
+    pub fn to_rotating_file(mut self, file: RotatingFile) -> Self {
+        self.rotating_files.push(file);
+        self
+    }
+
+    /// Install the global subscriber, returning the guards that flush each
+    /// output when dropped.
+    pub fn install(self) -> Result<Vec<WorkerGuard>, InitError> {
+        let filter = self
+            .filter_var
+            .map(|var| EnvFilter::try_from_env(var).unwrap_or_else(|_| EnvFilter::new("info")));
+
+        let mut guards = Vec::new();
+        let mut writer: Option<BoxMakeWriter> = None;
+        let mut add = |output| {
+            let (non_blocking, guard) = tracing_appender::non_blocking(output);
+            guards.push(guard);
+            writer = Some(match writer.take() {
+                Some(writer) => BoxMakeWriter::new(writer.and(non_blocking)),
+                None => BoxMakeWriter::new(non_blocking),
+            });
+        };
+
+        if self.stdout || (self.files.is_empty() && self.rotating_files.is_empty()) {
+            add(Output::Stdout(io::stdout()));
+        }
+        for (dir, rotation) in self.files {
+            let appender = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(file_prefix())
+                .filename_suffix("log")
+                .build(dir)
+                .map_err(InitError::File)?;
+            add(Output::File(appender));
+        }
+        for file in self.rotating_files {
+            add(Output::RotatingFile(file.open().map_err(InitError::Open)?));
+        }
+
+        let layer = FlatJsonLayer::new()
+            .with_format(self.format)
+            .with_writer(writer.expect("there is at least one output"));
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(layer)
+            .try_init()
+            .map_err(InitError::Install)?;
+
+        Ok(guards)
+    }
+}
+
+/// One of the outputs, so each can be given the same non-blocking writer.
+enum Output {
+    Stdout(io::Stdout),
+    File(RollingFileAppender),
+    RotatingFile(RotatingFileWriter),
+}
+
+impl io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(stdout) => stdout.write(buf),
+            Output::File(file) => file.write(buf),
+            Output::RotatingFile(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.flush(),
+            Output::RotatingFile(file) => file.flush(),
+        }
+    }
+}
+
+/// The name of the executable, to name log files after.
+pub(crate) fn file_prefix() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.file_stem()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "app".to_string())
+}
+
+/// Why logging could not be set up.
+#[derive(Debug)]
+pub enum InitError {
+    /// A log file could not be created.
+    File(rolling::InitError),
+    /// A rotating log file could not be opened.
+    Open(io::Error),
+    /// A global subscriber was already installed.
+    Install(TryInitError),
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::File(source) => write!(f, "failed to create log file: {}", source),
+            InitError::Open(source) => write!(f, "failed to open log file: {}", source),
+            InitError::Install(source) => write!(f, "failed to install subscriber: {}", source),
+        }
+    }
+}
+
+impl Error for InitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InitError::File(source) => Some(source),
+            InitError::Open(source) => Some(source),
+            InitError::Install(source) => Some(source),
+        }
+    }
+}
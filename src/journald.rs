@@ -0,0 +1,444 @@
+//! A `MakeWriter` that sends each event to systemd-journald with its native
+//! protocol.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    path::Path,
+    ptr,
+};
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{
+    sink::{text, LineFields},
+    syslog::severity,
+};
+
+/// `MakeWriter` that sends each event to systemd-journald, keeping every
+/// field of the line as a journal field, so they can be queried with
+/// `journalctl`:
+///
+/// ```txt
+/// MESSAGE=Order placed
+/// PRIORITY=6
+/// CODE_FILE=src/orders.rs
+/// CODE_LINE=42
+/// SYSLOG_IDENTIFIER=app
+/// LEVEL=INFO
+/// ORDER_ID=42
+/// ```
+///
+/// Field names are uppercased, and characters other than letters, digits
+/// and `_` are replaced by `_`, so `http.status` becomes `HTTP_STATUS`.
+/// Names the writer sets, or that journald gives a meaning, such as
+/// `PRIORITY` or `MESSAGE_ID`, are prefixed with `F_`, so a field can't
+/// override them. Values that are not strings are sent as JSON. Lines that
+/// are not JSON objects are sent as the `MESSAGE`.
+///
+/// Each event is one datagram. Events larger than the socket's maximum
+/// datagram size, usually around 200 KiB, are written to a sealed memory
+/// file instead, whose descriptor is sent to journald, as its protocol
+/// allows.
+pub struct JournaldWriter {
+    socket: UnixDatagram,
+    syslog_identifier: String,
+    message_key: String,
+}
+
+impl JournaldWriter {
+    /// Send to the journal at `/run/systemd/journal/socket`.
+    pub fn new() -> io::Result<Self> {
+        Self::unix("/run/systemd/journal/socket")
+    }
+
+    /// Send to the journal socket at `path`.
+    pub fn unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            socket,
+            syslog_identifier: crate::init::file_prefix(),
+            message_key: "message".to_string(),
+        })
+    }
+
+    /// Set `SYSLOG_IDENTIFIER`, which `journalctl -t` filters by. The name
+    /// of the executable by default.
+    pub fn with_syslog_identifier(mut self, syslog_identifier: impl Into<String>) -> Self {
+        self.syslog_identifier = syslog_identifier.into();
+        self
+    }
+
+    /// Set the key of the message field, if it was renamed.
+    pub fn with_message_key(mut self, message_key: impl Into<String>) -> Self {
+        self.message_key = message_key.into();
+        self
+    }
+
+    /// Encode `line`, written for the event `event`, as journal fields.
+    fn entry(&self, event: &EventMetadata, line: &[u8]) -> Vec<u8> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let mut entry = Vec::new();
+
+        let fields = LineFields::parse(line);
+        let (message, fields) = match fields {
+            Some(mut fields) => {
+                let message = fields.take(&self.message_key).map(|message| text(&message));
+                (message.map(String::into_bytes), fields.0)
+            }
+            None => (Some(line.to_vec()), Vec::new()),
+        };
+        if let Some(message) = message {
+            put_field(&mut entry, "MESSAGE", &message);
+        }
+        put_field(
+            &mut entry,
+            "PRIORITY",
+            severity(&event.level).to_string().as_bytes(),
+        );
+        if let Some(file) = &event.file {
+            put_field(&mut entry, "CODE_FILE", file.as_bytes());
+        }
+        if let Some(line) = event.line {
+            put_field(&mut entry, "CODE_LINE", line.to_string().as_bytes());
+        }
+        put_field(
+            &mut entry,
+            "SYSLOG_IDENTIFIER",
+            self.syslog_identifier.as_bytes(),
+        );
+
+        for (key, value) in fields {
+            put_field(&mut entry, &field_name(&key), text(&value).as_bytes());
+        }
+        entry
+    }
+
+    fn send(&self, entry: &[u8]) -> io::Result<()> {
+        match self.socket.send(entry) {
+            Ok(_) => Ok(()),
+            Err(error) if matches!(error.raw_os_error(), Some(libc::EMSGSIZE | libc::ENOBUFS)) => {
+                self.send_memfd(entry)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Send `entry` as a sealed memory file, for entries too large for a
+    /// datagram.
+    fn send_memfd(&self, entry: &[u8]) -> io::Result<()> {
+        let flags = libc::MFD_ALLOW_SEALING | libc::MFD_CLOEXEC;
+        // SAFETY: the name is a valid C string.
+        let fd = unsafe { libc::memfd_create(c"journald-entry".as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just created, and nothing else owns it.
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(entry)?;
+
+        // journald only accepts a file that can no longer change.
+        let seals =
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+        // SAFETY: `fd` is open for as long as `file` is.
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        send_fd(&self.socket, file.as_raw_fd())
+    }
+}
+
+/// Send `fd` over the connected `socket`, with no data.
+fn send_fd(socket: &UnixDatagram, fd: RawFd) -> io::Result<()> {
+    let fd_len = mem::size_of::<RawFd>() as u32;
+    // SAFETY: computing sizes has no preconditions.
+    let (space, len) = unsafe { (libc::CMSG_SPACE(fd_len), libc::CMSG_LEN(fd_len)) };
+    // As `u64`s, so the control message header is aligned.
+    let mut control = vec![0u64; (space as usize).div_ceil(mem::size_of::<u64>())];
+
+    // SAFETY: all zeros is a valid `msghdr`, without a name or data.
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = space as _;
+    // SAFETY: the control buffer is large enough and aligned for one control
+    // message holding one descriptor.
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = len as _;
+        ptr::write_unaligned(libc::CMSG_DATA(header).cast::<RawFd>(), fd);
+    }
+    // SAFETY: `message` and the buffer it points to outlive the call.
+    match unsafe { libc::sendmsg(socket.as_raw_fd(), &message, libc::MSG_NOSIGNAL) } {
+        sent if sent < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Journal fields the writer sets, or that journald gives a meaning.
+const RESERVED: &[&str] = &[
+    "MESSAGE",
+    "MESSAGE_ID",
+    "PRIORITY",
+    "CODE_FILE",
+    "CODE_LINE",
+    "CODE_FUNC",
+    "ERRNO",
+    "INVOCATION_ID",
+    "USER_INVOCATION_ID",
+    "SYSLOG_FACILITY",
+    "SYSLOG_IDENTIFIER",
+    "SYSLOG_PID",
+    "SYSLOG_TIMESTAMP",
+    "SYSLOG_RAW",
+    "DOCUMENTATION",
+    "TID",
+    "UNIT",
+    "USER_UNIT",
+];
+
+impl<'a> MakeWriter<'a> for JournaldWriter {
+    type Writer = JournaldEntry<'a>;
+
+    /// Events written without their metadata are sent at `INFO`.
+    fn make_writer(&'a self) -> Self::Writer {
+        JournaldEntry {
+            writer: self,
+            event: EventMetadata {
+                level: Level::INFO,
+                file: None,
+                line: None,
+            },
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        JournaldEntry {
+            writer: self,
+            event: EventMetadata {
+                level: *meta.level(),
+                file: meta.file().map(str::to_string),
+                line: meta.line(),
+            },
+            buffer: Vec::new(),
+        }
+    }
+}
+
+struct EventMetadata {
+    level: Level,
+    file: Option<String>,
+    line: Option<u32>,
+}
+
+/// Buffers the line written for one event, and sends it when dropped.
+pub struct JournaldEntry<'a> {
+    writer: &'a JournaldWriter,
+    event: EventMetadata,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for JournaldEntry<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for JournaldEntry<'_> {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let entry = self.writer.entry(&self.event, &self.buffer);
+        if let Err(error) = self.writer.send(&entry) {
+            eprintln!("failed to send log to journald: {}", error);
+        }
+    }
+}
+
+/// Append a field. Values with a newline are sent with their length, as a
+/// 64-bit little-endian integer, instead of after a `=`.
+fn put_field(entry: &mut Vec<u8>, name: &str, value: &[u8]) {
+    entry.extend_from_slice(name.as_bytes());
+    match value.contains(&b'\n') {
+        true => {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        }
+        false => entry.push(b'='),
+    }
+    entry.extend_from_slice(value);
+    entry.push(b'\n');
+}
+
+/// A valid journal field name for `key`: uppercase letters, digits and `_`,
+/// not starting with `_` or a digit, not reserved, and at most 64 characters.
+fn field_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    // Names starting with `_` are reserved for fields set by journald.
+    let name = name.trim_start_matches('_');
+    let name = match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() && !RESERVED.contains(&name) => name.to_string(),
+        _ => format!("F_{}", name),
+    };
+    name.chars().take(64).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{dispatcher, warn};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{FlatJsonLayer, SolinkJsonFormat};
+
+    /// Decode the fields of a journal entry.
+    fn fields(mut entry: &[u8]) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+        while !entry.is_empty() {
+            let end = entry
+                .iter()
+                .position(|b| *b == b'\n' || *b == b'=')
+                .unwrap();
+            let name = String::from_utf8(entry[..end].to_vec()).unwrap();
+            let value = match entry[end] {
+                b'=' => {
+                    let len = entry[end..].iter().position(|b| *b == b'\n').unwrap() - 1;
+                    entry = &entry[end + 1..];
+                    &entry[..len]
+                }
+                _ => {
+                    let len = u64::from_le_bytes(entry[end + 1..end + 9].try_into().unwrap());
+                    entry = &entry[end + 9..];
+                    &entry[..len as usize]
+                }
+            };
+            fields.push((name, String::from_utf8(value.to_vec()).unwrap()));
+            entry = &entry[value.len() + 1..];
+        }
+        fields
+    }
+
+    #[test]
+    fn should_send_every_field_to_the_journal() {
+        let path = std::env::temp_dir().join(format!("solink-journald-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let writer = JournaldWriter::unix(&path)
+            .unwrap()
+            .with_syslog_identifier("app");
+        let layer = FlatJsonLayer::new()
+            .with_format(SolinkJsonFormat::new().with_timestamp(false))
+            .with_writer(writer);
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        let line = dispatcher::with_default(&dispatch, || {
+            let span = tracing::info_span!("handle", http.status = 200, _id = 7);
+            let _s = span.enter();
+            warn!(order_id = 42, "Order\nplaced");
+            line!() - 1
+        });
+
+        let mut entry = [0; 1024];
+        let len = server.recv(&mut entry).unwrap();
+        let field = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(
+            fields(&entry[..len]),
+            [
+                field("MESSAGE", "Order\nplaced"),
+                field("PRIORITY", "4"),
+                field("CODE_FILE", file!()),
+                field("CODE_LINE", &line.to_string()),
+                field("SYSLOG_IDENTIFIER", "app"),
+                field("LEVEL", "WARN"),
+                field("TARGET", "solink_tracing_flat_json::journald::tests"),
+                field("ORDER_ID", "42"),
+                field("SPAN", "handle"),
+                field("HTTP_STATUS", "200"),
+                field("ID", "7"),
+            ],
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn should_prefix_fields_the_writer_or_journald_sets() {
+        let writer = JournaldWriter {
+            socket: UnixDatagram::unbound().unwrap(),
+            syslog_identifier: "app".to_string(),
+            message_key: "msg".to_string(),
+        };
+        let event = EventMetadata {
+            level: Level::INFO,
+            file: None,
+            line: None,
+        };
+        let line = br#"{"msg":"Hi","priority":"high","message":"x","syslog_identifier":"y","message_id":"z"}"#;
+        let field = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(
+            fields(&writer.entry(&event, line)),
+            [
+                field("MESSAGE", "Hi"),
+                field("PRIORITY", "6"),
+                field("SYSLOG_IDENTIFIER", "app"),
+                field("F_PRIORITY", "high"),
+                field("F_MESSAGE", "x"),
+                field("F_SYSLOG_IDENTIFIER", "y"),
+                field("F_MESSAGE_ID", "z"),
+            ],
+        );
+    }
+
+    #[test]
+    fn should_send_large_entries_as_a_memory_file() {
+        let path = std::env::temp_dir().join(format!("solink-journald-big-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let writer = JournaldWriter::unix(&path).unwrap();
+        let entry = vec![b'x'; 16 * 1024 * 1024];
+        writer.send(&entry).unwrap();
+
+        // Receive the descriptor, and read the entry from it.
+        let mut control = [0u64; 8];
+        // SAFETY: all zeros is a valid `msghdr`.
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = mem::size_of_val(&control) as _;
+        // SAFETY: the control buffer outlives the call.
+        let received = unsafe { libc::recvmsg(server.as_raw_fd(), &mut message, 0) };
+        assert_eq!(received, 0);
+        // SAFETY: the message has one control message, with a descriptor.
+        let mut file = unsafe {
+            let header = libc::CMSG_FIRSTHDR(&message);
+            assert_eq!((*header).cmsg_type, libc::SCM_RIGHTS);
+            File::from_raw_fd(ptr::read_unaligned(libc::CMSG_DATA(header).cast::<RawFd>()))
+        };
+        // The offset is shared with the writer, which left it at the end.
+        io::Seek::rewind(&mut file).unwrap();
+        let mut received = Vec::new();
+        io::Read::read_to_end(&mut file, &mut received).unwrap();
+        assert!(received == entry);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
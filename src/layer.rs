@@ -0,0 +1,145 @@
+//! A `Layer` that writes flat JSON on its own, without `fmt::layer()`.
+
+use std::io::{self, Write};
+
+use tracing::{
+    span::{Attributes, Id, Record},
+    Event, Subscriber,
+};
+use tracing_subscriber::{fmt::MakeWriter, layer::Context, registry::LookupSpan, Layer};
+
+use crate::{Encoding, SolinkJsonFormat, SpanFieldCache};
+
+/// `Layer` that writes every event as a line of flat JSON.
+///
+/// Unlike `SolinkJsonFormat`, which relies on the `fmt::Layer` being
+/// configured with `JsonFields`, this layer records span fields itself, so
+/// there is nothing else to set up:
+///
+/// ```rs
+///     use solink_tracing_flat_json::{FlatJsonLayer, SolinkJsonFormat};
+///
+///     let log_to_stderr = FlatJsonLayer::new()
+///         .with_format(SolinkJsonFormat::new().with_target(false))
+///         .with_writer(std::io::stderr);
+///     tracing_subscriber::registry().with(log_to_stderr).init();
+/// ```
+///
+/// With [`with_encoding`](Self::with_encoding), events can also be written
+/// as logfmt, or as length-prefixed MessagePack or CBOR frames for a sidecar
+/// reading from a socket.
+pub struct FlatJsonLayer<W = fn() -> io::Stdout> {
+    format: SolinkJsonFormat,
+    encoding: Encoding,
+    make_writer: W,
+    cache: SpanFieldCache,
+}
+
+impl FlatJsonLayer {
+    /// Create a layer that writes to stdout with the default format.
+    pub fn new() -> Self {
+        Self {
+            format: SolinkJsonFormat::new(),
+            encoding: Encoding::Json,
+            make_writer: io::stdout,
+            cache: SpanFieldCache::new(),
+        }
+    }
+}
+
+impl Default for FlatJsonLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W> FlatJsonLayer<W> {
+    /// Set the format used to write each event.
+    pub fn with_format(mut self, format: SolinkJsonFormat) -> Self {
+        self.cache = SpanFieldCache::for_format(&format);
+        self.format = format;
+        self
+    }
+
+    /// Set how each event is encoded. Defaults to [`Encoding::Json`].
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Set the `MakeWriter` each line is written to.
+    pub fn with_writer<W2>(self, make_writer: W2) -> FlatJsonLayer<W2>
+    where
+        W2: for<'writer> MakeWriter<'writer> + 'static,
+    {
+        FlatJsonLayer {
+            format: self.format,
+            encoding: self.encoding,
+            make_writer,
+            cache: self.cache,
+        }
+    }
+}
+
+impl<S, W> Layer<S> for FlatJsonLayer<W>
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    W: for<'writer> MakeWriter<'writer> + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        self.cache.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        self.cache.on_record(id, values, ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // Every span has cached fields, since this layer records them itself.
+        let _ = self.format.write_event(
+            event,
+            ctx.event_scope(event),
+            |_, _, _| Ok(()),
+            |fields, buffer| self.encoding.encode_fields(fields, buffer),
+            |line| {
+                let mut writer = self.make_writer.make_writer_for(event.metadata());
+                writer.write_all(line).map_err(|_| std::fmt::Error)
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{dispatcher, info};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::test_support::TestWriter;
+
+    #[tokio::test]
+    async fn should_write_without_fmt_layer() {
+        let writer = TestWriter::new();
+
+        let layer = FlatJsonLayer::new()
+            .with_format(SolinkJsonFormat::new().with_timestamp(false))
+            .with_writer(writer.clone());
+
+        let subscriber = Registry::default().with(layer);
+        let dispatch = dispatcher::Dispatch::new(subscriber);
+        dispatcher::with_default(&dispatch, || {
+            let span1 = tracing::info_span!("parent", x = 7);
+            let span2 = tracing::info_span!(parent: &span1, "child", y = 9);
+
+            let _s1 = span1.enter();
+            let _s2 = span2.enter();
+
+            info!(z = 10, "Test")
+        });
+
+        assert_eq!(
+            writer.contents().trim(),
+            r#"{"level":"INFO","target":"solink_tracing_flat_json::layer::tests","message":"Test","z":10,"span":"child","y":9,"x":7}"#,
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! How the level of each event is written.
+
+use serde_json::Value;
+use tracing::Level;
+
+/// How to write the level of each event.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LevelFormat {
+    /// `TRACE`, `DEBUG`, `INFO`, `WARN` or `ERROR`. This is the default.
+    #[default]
+    Uppercase,
+    /// `trace`, `debug`, `info`, `warn` or `error`.
+    Lowercase,
+    /// The severities of Google Cloud Logging: `DEBUG` (for both `TRACE` and
+    /// `DEBUG`), `INFO`, `WARNING` or `ERROR`.
+    Gcp,
+    /// The numeric levels of Bunyan: `10` for `TRACE`, `20` for `DEBUG`, `30`
+    /// for `INFO`, `40` for `WARN` and `50` for `ERROR`.
+    Bunyan,
+}
+
+impl LevelFormat {
+    pub(crate) fn format(self, level: &Level) -> Value {
+        match self {
+            LevelFormat::Uppercase => level.as_str().into(),
+            LevelFormat::Lowercase => level.as_str().to_ascii_lowercase().into(),
+            LevelFormat::Gcp => match *level {
+                Level::TRACE | Level::DEBUG => "DEBUG",
+                Level::INFO => "INFO",
+                Level::WARN => "WARNING",
+                Level::ERROR => "ERROR",
+            }
+            .into(),
+            LevelFormat::Bunyan => match *level {
+                Level::TRACE => 10,
+                Level::DEBUG => 20,
+                Level::INFO => 30,
+                Level::WARN => 40,
+                Level::ERROR => 50,
+            }
+            .into(),
+        }
+    }
+}
@@ -1,42 +1,600 @@
 #![doc = include_str!("../README.md")]
 
-use serde::ser::SerializeMap;
-use serde::Serializer;
-use tracing::{Event, Subscriber};
-use tracing_serde::AsSerde;
+mod builtin;
+mod cache;
+mod config;
+mod encoding;
+mod error;
+mod fields;
+mod gelf;
+#[cfg(feature = "http")]
+mod http;
+mod init;
+#[cfg(target_os = "linux")]
+mod journald;
+mod layer;
+mod level;
+mod logfmt;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+mod presets;
+mod pretty;
+#[cfg(feature = "redact")]
+mod redact;
+mod rotate;
+mod sink;
+mod syslog;
+#[cfg(test)]
+mod test_support;
+mod time;
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::OnceLock,
+};
+
+#[cfg(feature = "redact")]
+use std::sync::Arc;
+
+use serde::Serialize;
+use tracing::{Event, Metadata, Subscriber};
 use tracing_subscriber::{
-    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
-    registry::LookupSpan,
+    fmt::{
+        format::Writer, time::FormatTime, FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
+    registry::{LookupSpan, Scope, SpanRef},
 };
 
+pub use builtin::Builtin;
+use builtin::BuiltinKeys;
+use cache::CachedSpanFields;
+pub use cache::SpanFieldCache;
+pub use chrono::SecondsFormat;
+pub use config::{ConfigError, FormatConfig};
+pub use encoding::Encoding;
+pub use error::{ErrorStrategy, FormatError, FormatErrorCounter};
+pub use fields::{CollisionPolicy, Layout};
+use fields::{FieldSource, FieldValue, FieldVisitor, FlatFields};
+pub use gelf::{GelfMessage, GelfWriter, SolinkGelfFormat};
+#[cfg(feature = "http")]
+pub use http::{HttpGuard, HttpLine, HttpShipper, HttpWriter};
+pub use init::{init, Init, InitError};
+#[cfg(target_os = "linux")]
+pub use journald::{JournaldEntry, JournaldWriter};
+pub use layer::FlatJsonLayer;
+pub use level::LevelFormat;
+pub use logfmt::SolinkLogfmtFormat;
+#[cfg(feature = "opentelemetry")]
+pub use otel::OtelIds;
+pub use presets::{EcsFields, ECS_VERSION};
+pub use pretty::{SolinkFormat, SolinkPrettyFormat};
+#[cfg(feature = "redact")]
+pub use redact::{Redactor, Replacement, ValueScanner};
+pub use rotate::{EventWriter, RotatingFile, RotatingFileWriter};
+pub use syslog::{Facility, SyslogMessage, SyslogWriter};
+pub use time::{Clock, TimestampFormat};
+pub use tracing_appender::{non_blocking::WorkerGuard, rolling::Rotation};
+
+/// How to write the path from the root span to the current span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpanPath {
+    /// An array of span names, root first: `["parent","child"]`.
+    Array,
+    /// Span names joined with the given separator, root first:
+    /// `"parent>child"` for `SpanPath::Joined(">".into())`.
+    Joined(Cow<'static, str>),
+}
+
+/// How to write span and trace ids.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdFormat {
+    /// As a number. This is the default.
+    #[default]
+    Number,
+    /// As a string of 16 lowercase hex digits.
+    Hex,
+}
+
 /// `FormatEvent` for serializing data as JSON.
 ///
 /// Adapted from the example in https://github.com/tokio-rs/tracing/issues/2670.
 ///
 pub struct SolinkJsonFormat {
     add_timestamp: bool,
+    timestamp_format: TimestampFormat,
+    clock: Clock,
     add_target: bool,
+    add_span_name: bool,
+    span_path: Option<SpanPath>,
+    add_span_id: bool,
+    add_parent_span_id: bool,
+    add_root_span_id: bool,
+    id_format: IdFormat,
+    trace_id_prefix: Option<String>,
+    /// Whether `with_trace_id` was called, so OpenTelemetry ids don't turn
+    /// the trace id back on.
+    trace_id_chosen: bool,
+    trace_id_field: Option<String>,
+    #[cfg(feature = "opentelemetry")]
+    otel_ids: Option<OtelIds>,
+    level_format: LevelFormat,
+    require_message: bool,
+    add_source_location: bool,
+    add_file: bool,
+    add_line_number: bool,
+    add_module_path: bool,
+    add_thread_name: bool,
+    add_thread_id: bool,
+    add_pid: bool,
+    hostname: Option<String>,
+    keys: BuiltinKeys,
+    renames: HashMap<String, String>,
+    static_fields: Vec<(String, FieldValue)>,
+    #[cfg(feature = "redact")]
+    redactor: Option<Arc<Redactor>>,
+    layout: Layout,
+    /// Whether fields grouped by the layout are written as strings, for ECS
+    /// `labels`.
+    keyword_fields: bool,
+    collision_policy: CollisionPolicy,
+    error_strategy: ErrorStrategy,
+    errors: FormatErrorCounter,
+    /// Cleared by every builder that changes which keys are reserved.
+    reserved_keys: OnceLock<ReservedKeys>,
+}
+
+/// The keys user fields may not reuse, and the key each static field is
+/// written under.
+struct ReservedKeys {
+    keys: HashSet<String>,
+    static_keys: Vec<String>,
 }
 
 impl SolinkJsonFormat {
     pub fn new() -> Self {
         Self {
             add_timestamp: true,
+            timestamp_format: TimestampFormat::default(),
+            clock: Clock::default(),
             add_target: true,
+            add_span_name: true,
+            span_path: None,
+            add_span_id: false,
+            add_parent_span_id: false,
+            add_root_span_id: false,
+            id_format: IdFormat::default(),
+            trace_id_prefix: None,
+            trace_id_chosen: false,
+            trace_id_field: None,
+            #[cfg(feature = "opentelemetry")]
+            otel_ids: None,
+            level_format: LevelFormat::default(),
+            require_message: false,
+            add_source_location: false,
+            add_file: false,
+            add_line_number: false,
+            add_module_path: false,
+            add_thread_name: false,
+            add_thread_id: false,
+            add_pid: false,
+            hostname: None,
+            keys: BuiltinKeys::default(),
+            renames: HashMap::new(),
+            static_fields: Vec::new(),
+            #[cfg(feature = "redact")]
+            redactor: None,
+            layout: Layout::default(),
+            keyword_fields: false,
+            collision_policy: CollisionPolicy::default(),
+            error_strategy: ErrorStrategy::default(),
+            errors: FormatErrorCounter::default(),
+            reserved_keys: OnceLock::new(),
         }
     }
 
     /// Set whether to add a timestamp to the log.
     pub fn with_timestamp(mut self, add_timestamp: bool) -> Self {
+        self.reserved_keys.take();
         self.add_timestamp = add_timestamp;
         self
     }
 
+    /// Set how to write the timestamp. Defaults to an RFC 3339 string in UTC
+    /// with nanoseconds.
+    pub fn with_timestamp_format(mut self, timestamp_format: TimestampFormat) -> Self {
+        self.timestamp_format = timestamp_format;
+        self
+    }
+
+    /// Write the timestamp with a `FormatTime`, such as those in
+    /// `tracing_subscriber::fmt::time`.
+    pub fn with_timer(self, timer: impl FormatTime + Send + Sync + 'static) -> Self {
+        self.with_timestamp_format(TimestampFormat::Custom(Box::new(timer)))
+    }
+
+    /// Set the clock timestamps are read from. Defaults to the system clock.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Set whether to add the target to the log.
     pub fn with_target(mut self, add_target: bool) -> Self {
+        self.reserved_keys.take();
         self.add_target = add_target;
         self
     }
+
+    /// Set whether to add the name of the current span to the log as `span`.
+    pub fn with_span_name(mut self, add_span_name: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_span_name = add_span_name;
+        self
+    }
+
+    /// Set whether to add the path from the root span to the current span to
+    /// the log as `span_path`, and how to write it. Off by default.
+    pub fn with_span_path(mut self, span_path: Option<SpanPath>) -> Self {
+        self.reserved_keys.take();
+        self.span_path = span_path;
+        self
+    }
+
+    /// Set whether to add the id of the current span to the log as `span_id`.
+    pub fn with_span_id(mut self, add_span_id: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_span_id = add_span_id;
+        self
+    }
+
+    /// Set whether to add the id of the current span's parent to the log as
+    /// `parent_span_id`.
+    pub fn with_parent_span_id(mut self, add_parent_span_id: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_parent_span_id = add_parent_span_id;
+        self
+    }
+
+    /// Set whether to add the id of the outermost span in scope to the log as
+    /// `root_span_id`.
+    pub fn with_root_span_id(mut self, add_root_span_id: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_root_span_id = add_root_span_id;
+        self
+    }
+
+    /// Set how span ids are written. Defaults to [`IdFormat::Number`].
+    pub fn with_id_format(mut self, id_format: IdFormat) -> Self {
+        self.id_format = id_format;
+        self
+    }
+
+    /// Set whether to add a trace id to the log as `trace_id`, after
+    /// `prefix`. The id is read from the OpenTelemetry context with
+    /// `with_opentelemetry_ids`, or from the field set with
+    /// [`with_trace_id_field`](Self::with_trace_id_field). Nothing is written
+    /// for events without one: tracing's own span ids are only unique within
+    /// the process, so they are never used as trace ids.
+    pub fn with_trace_id(mut self, prefix: Option<impl Into<String>>) -> Self {
+        self.reserved_keys.take();
+        self.trace_id_prefix = prefix.map(Into::into);
+        self.trace_id_chosen = true;
+        self
+    }
+
+    /// Read the trace id from the event or span field `key`, such as a trace
+    /// id propagated in a request header and recorded on the request's span.
+    /// The innermost value is used, and the field is still written as well.
+    /// The OpenTelemetry context, if there is one, takes precedence.
+    pub fn with_trace_id_field(mut self, key: impl Into<String>) -> Self {
+        self.trace_id_field = Some(key.into());
+        self
+    }
+
+    /// Take the trace and span ids from the OpenTelemetry context that
+    /// `tracing-opentelemetry` attached to the current span, instead of from
+    /// tracing's own span ids, so that each line can be tied to its trace.
+    /// Turns on `span_id`, and `trace_id` unless it was turned off with
+    /// [`with_trace_id`](Self::with_trace_id), under the keys `ids` uses.
+    /// Nothing is written for spans outside a valid trace.
+    #[cfg(feature = "opentelemetry")]
+    pub fn with_opentelemetry_ids(mut self, ids: OtelIds) -> Self {
+        self.reserved_keys.take();
+        self.otel_ids = Some(ids);
+        self.add_span_id = true;
+        if !self.trace_id_chosen {
+            self.trace_id_prefix = Some(String::new());
+        }
+        match ids {
+            OtelIds::W3c => self,
+            OtelIds::Datadog => self
+                .with_key(Builtin::TraceId, "dd.trace_id")
+                .with_key(Builtin::SpanId, "dd.span_id"),
+        }
+    }
+
+    /// Set how the level is written. Defaults to [`LevelFormat::Uppercase`].
+    pub fn with_level_format(mut self, level_format: LevelFormat) -> Self {
+        self.level_format = level_format;
+        self
+    }
+
+    /// Set whether to write an empty message for events recorded without
+    /// one, for consumers that reject lines without a message. Off by
+    /// default.
+    pub fn with_required_message(mut self, require_message: bool) -> Self {
+        self.require_message = require_message;
+        self
+    }
+
+    /// Set whether to add an object with the source file, line and module
+    /// path the event was recorded in, as
+    /// `{"file":"src/main.rs","line":"42","function":"app::main"}`. The line
+    /// is a string, as Google Cloud Logging expects.
+    pub fn with_source_location(mut self, add_source_location: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_source_location = add_source_location;
+        self
+    }
+
+    /// Set whether to add the source file the event was recorded in.
+    pub fn with_file(mut self, add_file: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_file = add_file;
+        self
+    }
+
+    /// Set whether to add the line the event was recorded on.
+    pub fn with_line_number(mut self, add_line_number: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_line_number = add_line_number;
+        self
+    }
+
+    /// Set whether to add the module path the event was recorded in.
+    pub fn with_module_path(mut self, add_module_path: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_module_path = add_module_path;
+        self
+    }
+
+    /// Set whether to add the name of the thread the event was recorded on.
+    /// Nothing is added for unnamed threads.
+    pub fn with_thread_name(mut self, add_thread_name: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_thread_name = add_thread_name;
+        self
+    }
+
+    /// Set whether to add the id of the thread the event was recorded on.
+    pub fn with_thread_id(mut self, add_thread_id: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_thread_id = add_thread_id;
+        self
+    }
+
+    /// Set whether to add the id of the current process.
+    pub fn with_pid(mut self, add_pid: bool) -> Self {
+        self.reserved_keys.take();
+        self.add_pid = add_pid;
+        self
+    }
+
+    /// Set whether to add the name of the host. The name is looked up once,
+    /// when this is called.
+    pub fn with_hostname(mut self, add_hostname: bool) -> Self {
+        self.reserved_keys.take();
+        self.hostname =
+            add_hostname.then(|| gethostname::gethostname().to_string_lossy().into_owned());
+        self
+    }
+
+    /// Set the key a built-in field is written under.
+    pub fn with_key(mut self, builtin: Builtin, key: impl Into<Cow<'static, str>>) -> Self {
+        self.reserved_keys.take();
+        self.keys.set(builtin, key);
+        self
+    }
+
+    /// Write a field under another name.
+    ///
+    /// If `from` is the default key of a built-in field, such as `"timestamp"`
+    /// or `"level"`, that field is renamed, just like with
+    /// [`with_key`](Self::with_key). Otherwise every event and span field
+    /// named `from` is renamed, before any collisions are resolved.
+    pub fn with_rename(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.reserved_keys.take();
+        let (from, to) = (from.into(), to.into());
+        match Builtin::ALL
+            .into_iter()
+            .find(|builtin| builtin.default_key() == from)
+        {
+            Some(builtin) => self.keys.set(builtin, to),
+            None => {
+                self.renames.insert(from, to);
+            }
+        }
+        self
+    }
+
+    /// Rename every field in `renames`, as with [`with_rename`](Self::with_rename).
+    pub fn with_renames<K, V>(self, renames: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        renames
+            .into_iter()
+            .fold(self, |format, (from, to)| format.with_rename(from, to))
+    }
+
+    /// Add a field with the same value to every line, such as the name of the
+    /// service. Static fields are written in the order they were added, after
+    /// the level, target, source location, thread and process fields, and
+    /// before the event's fields and the span name, path and ids. They can't
+    /// be displaced by event or span fields, and one with the same key as an
+    /// enabled built-in field is written as `static.<key>` instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` can't be represented as JSON, for example if it is
+    /// a map with non-string keys. Use
+    /// [`try_with_static_field`](Self::try_with_static_field) for values that
+    /// might not be.
+    pub fn with_static_field(self, key: impl Into<String>, value: impl Serialize) -> Self {
+        let key = key.into();
+        match self.try_with_static_field(key.clone(), value) {
+            Ok(format) => format,
+            Err(error) => panic!("static field `{}` is not valid JSON: {}", key, error),
+        }
+    }
+
+    /// Like [`with_static_field`](Self::with_static_field), but fails instead
+    /// of panicking if `value` can't be represented as JSON.
+    pub fn try_with_static_field(
+        mut self,
+        key: impl Into<String>,
+        value: impl Serialize,
+    ) -> Result<Self, serde_json::Error> {
+        let value = serde_json::to_value(value)?;
+        let key = key.into();
+        self.reserved_keys.take();
+        self.static_fields.retain(|(existing, _)| *existing != key);
+        self.static_fields.push((key, FieldValue::cached(value)));
+        Ok(self)
+    }
+
+    /// Add a static field with the value of the environment variable `var`,
+    /// if it is set.
+    pub fn with_env_field(self, key: impl Into<String>, var: &str) -> Self {
+        match std::env::var(var) {
+            Ok(value) => self.with_static_field(key, value),
+            Err(_) => self,
+        }
+    }
+
+    /// Add the static fields every service is expected to have, from the
+    /// environment variables that are set among:
+    ///
+    /// | Field         | Variable          |
+    /// |---------------|-------------------|
+    /// | `service`     | `SERVICE_NAME`    |
+    /// | `version`     | `SERVICE_VERSION` |
+    /// | `environment` | `ENVIRONMENT`     |
+    /// | `region`      | `REGION`          |
+    pub fn with_service_fields_from_env(self) -> Self {
+        self.with_env_field("service", "SERVICE_NAME")
+            .with_env_field("version", "SERVICE_VERSION")
+            .with_env_field("environment", "ENVIRONMENT")
+            .with_env_field("region", "REGION")
+    }
+
+    /// Redact sensitive event and span fields with `redactor`.
+    #[cfg(feature = "redact")]
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = Some(Arc::new(redactor));
+        self
+    }
+
+    /// Set how event and span fields are laid out. Defaults to
+    /// [`Layout::Flat`].
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.reserved_keys.take();
+        self.layout = layout;
+        self
+    }
+
+    /// Set how to resolve fields that share a name across the event and its
+    /// spans. Defaults to [`CollisionPolicy::InnermostWins`].
+    pub fn with_collision_policy(mut self, collision_policy: CollisionPolicy) -> Self {
+        self.collision_policy = collision_policy;
+        self
+    }
+
+    /// Set what to do with events that fail to format. Defaults to
+    /// [`ErrorStrategy::Fallback`].
+    pub fn with_error_strategy(mut self, error_strategy: ErrorStrategy) -> Self {
+        self.error_strategy = error_strategy;
+        self
+    }
+
+    /// Returns a handle to the number of events this formatter failed to
+    /// format. Take it before passing the formatter to `event_format`.
+    pub fn error_counter(&self) -> FormatErrorCounter {
+        self.errors.clone()
+    }
+
+    fn is_enabled(&self, builtin: Builtin) -> bool {
+        match builtin {
+            Builtin::Timestamp => self.add_timestamp,
+            Builtin::Level => true,
+            Builtin::Span => self.add_span_name,
+            Builtin::Target => self.add_target,
+            Builtin::SpanPath => self.span_path.is_some(),
+            Builtin::SpanId => self.add_span_id,
+            Builtin::ParentSpanId => self.add_parent_span_id,
+            Builtin::RootSpanId => self.add_root_span_id,
+            Builtin::File => self.add_file,
+            Builtin::Line => self.add_line_number,
+            Builtin::ModulePath => self.add_module_path,
+            Builtin::ThreadName => self.add_thread_name,
+            Builtin::ThreadId => self.add_thread_id,
+            Builtin::Pid => self.add_pid,
+            Builtin::Hostname => self.hostname.is_some(),
+            Builtin::SourceLocation => self.add_source_location,
+            Builtin::TraceId => self.trace_id_prefix.is_some(),
+            Builtin::Spans => self.layout == Layout::NestedBySpan,
+            Builtin::Fields => {
+                matches!(self.layout, Layout::FieldsObject | Layout::AllFieldsObject)
+            }
+        }
+    }
+
+    /// The key of the object the layout groups fields under, if any.
+    fn group_key(&self) -> &str {
+        match self.layout {
+            Layout::NestedBySpan => self.keys.get(Builtin::Spans),
+            Layout::FieldsObject | Layout::AllFieldsObject => self.keys.get(Builtin::Fields),
+            Layout::Flat | Layout::PrefixedFlat => "",
+        }
+    }
+
+    /// Keys written by the formatter itself, which user fields may not reuse.
+    /// Worked out once, for the first event.
+    ///
+    /// Static fields with the key of an enabled built-in field are renamed to
+    /// `static.<key>`, since built-in fields always keep their key.
+    fn reserved_keys(&self) -> &ReservedKeys {
+        self.reserved_keys.get_or_init(|| {
+            let mut keys: HashSet<String> = Builtin::ALL
+                .into_iter()
+                .filter(|builtin| self.is_enabled(*builtin))
+                .map(|builtin| self.keys.get(builtin).to_string())
+                .collect();
+            let static_keys = self
+                .static_fields
+                .iter()
+                .map(|(key, _)| {
+                    let key = match keys.contains(key) {
+                        true => (1..)
+                            .map(|n| match n {
+                                1 => format!("static.{}", key),
+                                n => format!("static.{}.{}", key, n),
+                            })
+                            .find(|candidate| !keys.contains(candidate))
+                            .expect("ran out of suffixes"),
+                        false => key.clone(),
+                    };
+                    keys.insert(key.clone());
+                    key
+                })
+                .collect();
+            ReservedKeys { keys, static_keys }
+        })
+    }
 }
 
 impl Default for SolinkJsonFormat {
@@ -55,97 +613,486 @@ where
         ctx: &FmtContext<'_, S, N>,
         mut writer: Writer<'_>,
         event: &Event<'_>,
-    ) -> std::fmt::Result
+    ) -> fmt::Result
     where
         S: Subscriber + for<'a> LookupSpan<'a>,
     {
-        let meta = event.metadata();
+        self.write_event(
+            event,
+            ctx.event_scope(),
+            insert_formatted_fields::<N, S>,
+            |fields, buffer| Encoding::Json.encode_fields(fields, buffer),
+            |line| write_str(&mut writer, line),
+        )
+    }
+}
+
+impl SolinkJsonFormat {
+    /// Encode `event` as a single frame, such as a line ending with a
+    /// newline, and pass it to `write`, or handle the error as configured if
+    /// formatting fails.
+    ///
+    /// Span fields are copied from the span's `CachedSpanFields`, or added by
+    /// `uncached_fields` for spans that have none.
+    pub(crate) fn write_event<'a, R>(
+        &self,
+        event: &Event<'_>,
+        scope: Option<Scope<'a, R>>,
+        uncached_fields: impl Fn(
+            &SpanRef<'a, R>,
+            FieldSource,
+            &mut FlatFields,
+        ) -> Result<(), FormatError>,
+        encode: impl Fn(&FlatFields, &mut Vec<u8>) -> Result<(), FormatError>,
+        mut write: impl FnMut(&[u8]) -> fmt::Result,
+    ) -> fmt::Result
+    where
+        R: LookupSpan<'a>,
+    {
+        let mut fields = self.flat_fields();
+        let result = with_buffer(|buffer| {
+            self.collect_fields(event, scope, uncached_fields, &mut fields)?;
+            encode(&fields, buffer)?;
+            Ok::<_, FormatError>(write(buffer))
+        });
+
+        match result {
+            Ok(written) => written,
+            Err(error) => {
+                self.errors.increment();
+                match self.error_strategy {
+                    ErrorStrategy::Drop => Ok(()),
+                    ErrorStrategy::Fallback => {
+                        let error = serde_json::Value::from(error.to_string());
+                        let fallback = fallback_fields(&fields, self.message_key(), &error);
+                        let mut line = Vec::new();
+                        match encode(&fallback, &mut line) {
+                            Ok(()) => write(&line),
+                            Err(_) => Ok(()),
+                        }
+                    }
+                    ErrorStrategy::ReportToStderr => {
+                        eprintln!("solink-tracing-flat-json: dropped an event: {}", error);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serialize `event` with any `serde::Serializer`, as a map of the fields
+    /// a line of JSON would have. This is how to write events in encodings
+    /// other than those of [`Encoding`], from a custom `Layer`.
+    ///
+    /// Span fields are read from the [`SpanFieldCache`], which must be
+    /// installed. Errors are returned as they are, rather than handled as
+    /// configured with [`with_error_strategy`](Self::with_error_strategy).
+    pub fn serialize_event<'a, R, S>(
+        &self,
+        event: &Event<'_>,
+        scope: Option<Scope<'a, R>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        R: LookupSpan<'a>,
+        S: serde::Serializer,
+    {
+        let mut fields = self.flat_fields();
+        self.collect_fields(event, scope, |_, _, _| Ok(()), &mut fields)
+            .map_err(serde::ser::Error::custom)?;
+        // A temporary in the tail expression would outlive `fields`.
+        let serializable = fields.serializable(false);
+        serializable.serialize(serializer)
+    }
+
+    /// The key the event's message is written under.
+    fn message_key(&self) -> &str {
+        self.renames
+            .get("message")
+            .map_or("message", String::as_str)
+    }
+
+    fn flat_fields(&self) -> FlatFields<'_> {
+        let fields = FlatFields::new(self.collision_policy, &self.reserved_keys().keys)
+            .with_renames(&self.renames)
+            .with_layout(self.layout, self.group_key())
+            .with_keyword_group(self.keyword_fields)
+            .with_watch(self.trace_id_field.as_deref());
+        #[cfg(feature = "redact")]
+        let fields = fields.with_redactor(self.redactor.as_deref());
+        fields
+    }
 
-        let mut s = Vec::<u8>::new();
-        let mut serializer = serde_json::Serializer::new(&mut s);
-        let mut serializer_map = serializer.serialize_map(None).unwrap();
+    /// The redactor event and span fields are redacted with, if any.
+    #[cfg(feature = "redact")]
+    pub(crate) fn redactor(&self) -> Option<&Arc<Redactor>> {
+        self.redactor.as_ref()
+    }
+
+    /// Collect the built-in fields, then the event's fields, then the fields
+    /// of every span in scope, innermost first.
+    ///
+    /// Built-in and event fields are always collected before anything can
+    /// fail, so that a fallback line can be written from them.
+    fn collect_fields<'a, 'k, R>(
+        &'k self,
+        event: &Event<'_>,
+        scope: Option<Scope<'a, R>>,
+        uncached_fields: impl Fn(
+            &SpanRef<'a, R>,
+            FieldSource,
+            &mut FlatFields,
+        ) -> Result<(), FormatError>,
+        fields: &mut FlatFields<'k>,
+    ) -> Result<(), FormatError>
+    where
+        R: LookupSpan<'a>,
+    {
+        let meta = event.metadata();
 
         if self.add_timestamp {
-            let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true);
-            serializer_map
-                .serialize_entry("timestamp", &timestamp)
-                .unwrap();
+            let timestamp = self.timestamp_format.format(&self.clock);
+            fields.push_builtin(self.keys.get(Builtin::Timestamp), timestamp);
         }
 
-        serializer_map
-            .serialize_entry("level", &meta.level().as_serde())
-            .unwrap();
+        fields.push_builtin(
+            self.keys.get(Builtin::Level),
+            self.level_format.format(meta.level()),
+        );
 
         if self.add_target {
-            serializer_map
-                .serialize_entry("target", meta.target())
-                .unwrap();
+            fields.push_builtin(self.keys.get(Builtin::Target), meta.target());
         }
 
-        let mut visitor = tracing_serde::SerdeMapVisitor::new(serializer_map);
-        event.record(&mut visitor);
-        let mut serializer_map = visitor.take_serializer().unwrap();
+        self.push_metadata(meta, fields);
 
-        if let Some(scope) = ctx.event_scope() {
-            for (index, span) in scope.enumerate() {
-                if index == 0 {
-                    serializer_map.serialize_entry("span", span.name()).unwrap();
-                }
+        let static_keys = &self.reserved_keys().static_keys;
+        for ((_, value), key) in self.static_fields.iter().zip(static_keys) {
+            fields.push_builtin_value(key, value.clone());
+        }
+
+        let mut has_message = false;
+        event.record(&mut FieldVisitor::new(|key, value| {
+            has_message |= key == "message";
+            fields.insert(FieldSource::Event, key, value)
+        }));
+        if self.require_message && !has_message {
+            fields.insert(FieldSource::Event, "message", serde_json::Value::from(""));
+        }
+
+        // Innermost first.
+        let spans: Vec<_> = scope.into_iter().flatten().collect();
+        if let Some(span) = spans.first().filter(|_| self.add_span_name) {
+            fields.push_builtin(self.keys.get(Builtin::Span), span.name());
+        }
+        let has_trace_id = self.push_span_ancestry(&spans, fields);
 
-                let ext = span.extensions();
-                if let Some(data) = ext.get::<FormattedFields<N>>() {
-                    if let serde_json::Value::Object(fields) =
-                        serde_json::from_str::<serde_json::Value>(data).unwrap()
+        for (depth, span) in spans.iter().enumerate() {
+            let source = FieldSource::Span {
+                name: span.name(),
+                depth,
+            };
+            let ext = span.extensions();
+            match ext.get::<CachedSpanFields>() {
+                Some(cached) => {
+                    // Fields the cache already redacted with this redactor.
+                    #[cfg(feature = "redact")]
+                    if let Some(redacted) = self
+                        .redactor
+                        .as_ref()
+                        .and_then(|redactor| cached.redacted_by(redactor))
                     {
-                        for field in fields {
-                            serializer_map.serialize_entry(&field.0, &field.1).unwrap();
+                        for (key, value) in redacted {
+                            fields.insert_redacted(source, key, value.clone());
                         }
+                        continue;
                     }
+                    for (key, value) in cached.iter() {
+                        fields.insert(source, key, value.clone());
+                    }
+                }
+                None => {
+                    drop(ext);
+                    uncached_fields(span, source, fields)?;
                 }
             }
         }
 
-        serializer_map.end().unwrap();
-
-        writer.write_str(std::str::from_utf8(&s).unwrap()).unwrap();
-        writeln!(writer)
+        if !has_trace_id {
+            self.push_field_trace_id(fields);
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// Add the source location, thread and process fields that are enabled.
+    fn push_metadata<'k>(&'k self, meta: &Metadata<'_>, fields: &mut FlatFields<'k>) {
+        if self.add_source_location {
+            let mut location = serde_json::Map::new();
+            if let Some(file) = meta.file() {
+                location.insert("file".to_string(), file.into());
+            }
+            if let Some(line) = meta.line() {
+                location.insert("line".to_string(), line.to_string().into());
+            }
+            if let Some(module_path) = meta.module_path() {
+                location.insert("function".to_string(), module_path.into());
+            }
+            fields.push_builtin(self.keys.get(Builtin::SourceLocation), location);
+        }
+        if self.add_module_path {
+            if let Some(module_path) = meta.module_path() {
+                fields.push_builtin(self.keys.get(Builtin::ModulePath), module_path);
+            }
+        }
+        if self.add_file {
+            if let Some(file) = meta.file() {
+                fields.push_builtin(self.keys.get(Builtin::File), file);
+            }
+        }
+        if self.add_line_number {
+            if let Some(line) = meta.line() {
+                fields.push_builtin(self.keys.get(Builtin::Line), line);
+            }
+        }
 
-    use std::{
-        io,
-        sync::{Arc, Mutex},
-    };
+        if self.add_thread_name || self.add_thread_id {
+            let thread = std::thread::current();
+            if self.add_thread_name {
+                if let Some(name) = thread.name() {
+                    fields.push_builtin(self.keys.get(Builtin::ThreadName), name);
+                }
+            }
+            if self.add_thread_id {
+                let id = format!("{:?}", thread.id());
+                fields.push_builtin(self.keys.get(Builtin::ThreadId), id);
+            }
+        }
 
-    use tracing::{dispatcher, info};
-    use tracing_subscriber::{fmt::format::JsonFields, Layer, Registry};
+        if self.add_pid {
+            fields.push_builtin(self.keys.get(Builtin::Pid), std::process::id());
+        }
+        if let Some(hostname) = &self.hostname {
+            fields.push_builtin(self.keys.get(Builtin::Hostname), hostname.as_str());
+        }
+    }
 
-    use super::*;
+    /// Add the span path and span ids that are enabled, given the spans in
+    /// scope, innermost first.
+    fn push_span_ancestry<'a, 'k, R>(
+        &'k self,
+        spans: &[SpanRef<'a, R>],
+        fields: &mut FlatFields<'k>,
+    ) -> bool
+    where
+        R: LookupSpan<'a>,
+    {
+        if spans.is_empty() {
+            return false;
+        }
+
+        if let Some(span_path) = &self.span_path {
+            let key = self.keys.get(Builtin::SpanPath);
+            let names = spans.iter().rev().map(|span| span.name());
+            match span_path {
+                SpanPath::Array => fields.push_builtin(key, names.collect::<Vec<_>>()),
+                SpanPath::Joined(separator) => {
+                    fields.push_builtin(key, names.collect::<Vec<_>>().join(separator.as_ref()))
+                }
+            }
+        }
 
-    #[derive(Debug, Clone)]
-    struct TestWriter {
-        data: Arc<Mutex<Vec<u8>>>,
+        let has_trace_id = self.push_trace_and_span_id(spans, fields);
+        let id = |span: Option<&SpanRef<'a, R>>| span.map(|span| self.format_id(span.id()));
+        if self.add_parent_span_id {
+            if let Some(id) = id(spans.get(1)) {
+                fields.push_builtin(self.keys.get(Builtin::ParentSpanId), id);
+            }
+        }
+        if self.add_root_span_id {
+            if let Some(id) = id(spans.last()) {
+                fields.push_builtin(self.keys.get(Builtin::RootSpanId), id);
+            }
+        }
+        has_trace_id
     }
 
-    impl TestWriter {
-        fn new() -> Self {
-            Self {
-                data: Arc::new(Mutex::new(Vec::new())),
+    /// Add the trace id and the id of the current span, if enabled, given
+    /// the spans in scope, innermost first. Returns whether a trace id was
+    /// added.
+    fn push_trace_and_span_id<'a, 'k, R>(
+        &'k self,
+        spans: &[SpanRef<'a, R>],
+        fields: &mut FlatFields<'k>,
+    ) -> bool
+    where
+        R: LookupSpan<'a>,
+    {
+        #[cfg(feature = "opentelemetry")]
+        if let Some(ids) = self.otel_ids {
+            let Some((trace_id, span_id)) = OtelIds::read(&spans[0]) else {
+                return false;
+            };
+            let prefix = self.trace_id_prefix.as_deref();
+            if let Some(prefix) = prefix {
+                let trace_id = ids.trace_id(prefix, trace_id);
+                fields.push_builtin(self.keys.get(Builtin::TraceId), trace_id);
+            }
+            if self.add_span_id {
+                fields.push_builtin(self.keys.get(Builtin::SpanId), ids.span_id(span_id));
             }
+            return prefix.is_some();
         }
+
+        if self.add_span_id {
+            let span_id = self.format_id(spans[0].id());
+            fields.push_builtin(self.keys.get(Builtin::SpanId), span_id);
+        }
+        false
     }
 
-    impl io::Write for TestWriter {
-        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-            self.data.lock().unwrap().write(buf)
+    /// Add the trace id read from the field set with `with_trace_id_field`,
+    /// if it was recorded.
+    fn push_field_trace_id<'k>(&'k self, fields: &mut FlatFields<'k>) {
+        let Some(prefix) = self.trace_id_prefix.as_deref() else {
+            return;
+        };
+        let trace_id = match fields.watched().map(FieldValue::as_value) {
+            Some(serde_json::Value::String(id)) => format!("{}{}", prefix, id),
+            Some(id) => format!("{}{}", prefix, id),
+            None => return,
+        };
+        fields.push_builtin(self.keys.get(Builtin::TraceId), trace_id);
+    }
+
+    fn format_id(&self, id: tracing::span::Id) -> serde_json::Value {
+        match self.id_format {
+            IdFormat::Number => id.into_u64().into(),
+            IdFormat::Hex => format!("{:016x}", id.into_u64()).into(),
         }
+    }
+}
+
+/// Parse the span's `FormattedFields`, written by a `fmt::Layer`, and add
+/// them to `fields`.
+fn insert_formatted_fields<'a, N, R>(
+    span: &SpanRef<'a, R>,
+    source: FieldSource,
+    fields: &mut FlatFields,
+) -> Result<(), FormatError>
+where
+    N: for<'writer> FormatFields<'writer> + 'static,
+    R: LookupSpan<'a>,
+{
+    let ext = span.extensions();
+    let Some(data) = ext.get::<FormattedFields<N>>() else {
+        return Ok(());
+    };
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    let span_fields = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(data)
+        .map_err(|source| FormatError::SpanFields {
+            span: span.name(),
+            source,
+        })?;
+    for (key, value) in span_fields {
+        fields.insert(source, key, value);
+    }
+
+    Ok(())
+}
+
+/// Buffers larger than this are not kept around between events.
+const MAX_RETAINED_BUFFER: usize = 64 * 1024;
+
+thread_local! {
+    static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
 
-        fn flush(&mut self) -> io::Result<()> {
-            Ok(())
+/// Run `f` with an empty buffer, reusing this thread's buffer unless it is
+/// already in use (if writing an event logs another one) or gone (while the
+/// thread is shutting down).
+fn with_buffer<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+    let mut f = Some(f);
+    let reused = BUFFER.try_with(|buffer| {
+        let mut buffer = buffer.try_borrow_mut().ok()?;
+        buffer.clear();
+        let result = (f.take().expect("called once"))(&mut buffer);
+        if buffer.capacity() > MAX_RETAINED_BUFFER {
+            *buffer = Vec::new();
         }
+        Some(result)
+    });
+
+    match reused {
+        Ok(Some(result)) => result,
+        _ => (f.take().expect("called once"))(&mut Vec::new()),
+    }
+}
+
+/// Only the built-in fields, the event's message and the error, to write in
+/// place of an event that could not be formatted.
+fn fallback_fields<'f>(
+    fields: &'f FlatFields,
+    message_key: &'f str,
+    error: &serde_json::Value,
+) -> FlatFields<'f> {
+    let mut fallback = FlatFields::new(CollisionPolicy::default(), fields.reserved());
+    for (key, value) in fields.builtins() {
+        fallback.push_builtin(key, value.clone());
+    }
+    if let Some(message) = fields.get(message_key) {
+        fallback.push_builtin(message_key, message.clone());
+    }
+    fallback.push_builtin("log_format_error", error.clone());
+    fallback
+}
+
+/// Write a line to a `fmt::Write`. Text encodings are always UTF-8.
+fn write_str(writer: &mut Writer<'_>, line: &[u8]) -> fmt::Result {
+    let line = std::str::from_utf8(line).map_err(|_| fmt::Error)?;
+    writer.write_str(line)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tracing::{dispatcher, info};
+    use tracing_subscriber::{
+        fmt::format::{DefaultFields, JsonFields},
+        layer::SubscriberExt,
+        Layer, Registry,
+    };
+
+    use super::*;
+    use crate::test_support::TestWriter;
+
+    /// Runs `f` with a subscriber that formats with `format`, and returns
+    /// everything that was written.
+    fn capture(format: SolinkJsonFormat, f: impl FnOnce()) -> String {
+        capture_with_fields(format, JsonFields::default(), f)
+    }
+
+    fn capture_with_fields<N>(format: SolinkJsonFormat, fmt_fields: N, f: impl FnOnce()) -> String
+    where
+        N: for<'writer> FormatFields<'writer> + Send + Sync + 'static,
+    {
+        let writer = TestWriter::new();
+
+        let log_to_file = {
+            let writer = writer.clone();
+            tracing_subscriber::fmt::layer()
+                .event_format(format)
+                .fmt_fields(fmt_fields)
+                .with_writer(move || writer.clone())
+        };
+
+        let subscriber = log_to_file.with_subscriber(Registry::default());
+        let dispatch = dispatcher::Dispatch::new(subscriber);
+        dispatcher::with_default(&dispatch, f);
+
+        writer.contents()
     }
 
     #[tokio::test]
@@ -202,8 +1149,357 @@ mod tests {
         let data = writer.data.lock().unwrap();
         let data = std::str::from_utf8(&data).unwrap();
         assert_eq!(
-        data.trim(),
-        r#"{"level":"INFO","target":"solink_tracing_flat_json::tests","message":"Event with no fields"}"#,
-    );
+            data.trim(),
+            r#"{"level":"INFO","target":"solink_tracing_flat_json::tests","message":"Event with no fields"}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn should_emit_each_key_once() {
+        let log = || {
+            let span1 = tracing::info_span!("parent", id = 1, level = "high");
+            let span2 = tracing::info_span!(parent: &span1, "child", id = 2);
+
+            let _s1 = span1.enter();
+            let _s2 = span2.enter();
+
+            info!(id = 3, "Test")
+        };
+
+        let data = capture(SolinkJsonFormat::new().with_timestamp(false), log);
+        assert_eq!(
+            data.trim(),
+            r#"{"level":"INFO","target":"solink_tracing_flat_json::tests","message":"Test","id":3,"span":"child","parent.level":"high"}"#,
+        );
+
+        let data = capture(
+            SolinkJsonFormat::new()
+                .with_timestamp(false)
+                .with_collision_policy(CollisionPolicy::PrefixWithSpan),
+            log,
+        );
+        assert_eq!(
+            data.trim(),
+            r#"{"level":"INFO","target":"solink_tracing_flat_json::tests","message":"Test","id":3,"span":"child","child.id":2,"parent.id":1,"parent.level":"high"}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn should_fall_back_when_span_fields_are_not_json() {
+        let format = SolinkJsonFormat::new().with_timestamp(false);
+        let errors = format.error_counter();
+
+        let data = capture_with_fields(format, DefaultFields::new(), || {
+            let span = tracing::info_span!("parent", x = 7);
+            let _s = span.enter();
+
+            info!(z = 10, "Test")
+        });
+        assert_eq!(
+            data.trim(),
+            r#"{"level":"INFO","target":"solink_tracing_flat_json::tests","span":"parent","message":"Test","log_format_error":"fields of span `parent` are not a JSON object: expected value at line 1 column 1"}"#,
+        );
+        assert_eq!(errors.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_drop_events_that_fail_to_format() {
+        let format = SolinkJsonFormat::new().with_error_strategy(ErrorStrategy::Drop);
+        let errors = format.error_counter();
+
+        let data = capture_with_fields(format, DefaultFields::new(), || {
+            let span = tracing::info_span!("parent", x = 7);
+            let _s = span.enter();
+
+            info!("dropped");
+            info!("also dropped");
+        });
+        assert_eq!(data, "");
+        assert_eq!(errors.count(), 2);
+    }
+
+    #[tokio::test]
+    async fn should_use_cached_span_fields() {
+        let writer = TestWriter::new();
+
+        let log_to_file = {
+            let writer = writer.clone();
+            tracing_subscriber::fmt::layer()
+                .event_format(SolinkJsonFormat::new().with_timestamp(false))
+                .with_writer(move || writer.clone())
+        };
+
+        // `DefaultFields` doesn't write JSON, so these can only come from the cache.
+        let subscriber = Registry::default()
+            .with(SpanFieldCache::new())
+            .with(log_to_file);
+        let dispatch = dispatcher::Dispatch::new(subscriber);
+        dispatcher::with_default(&dispatch, || {
+            let span = tracing::info_span!("parent", x = 7, y = tracing::field::Empty);
+            let _s = span.enter();
+
+            info!("before");
+            span.record("y", "recorded");
+            span.record("x", 8);
+            info!("after");
+        });
+
+        let data = writer.data.lock().unwrap();
+        let data = std::str::from_utf8(&data).unwrap();
+        assert_eq!(
+            data,
+            concat!(
+                r#"{"level":"INFO","target":"solink_tracing_flat_json::tests","message":"before","span":"parent","x":7}"#,
+                "\n",
+                r#"{"level":"INFO","target":"solink_tracing_flat_json::tests","message":"after","span":"parent","x":8,"y":"recorded"}"#,
+                "\n",
+            ),
+        );
+    }
+
+    #[tokio::test]
+    async fn should_write_span_ancestry() {
+        let log = || {
+            let span1 = tracing::info_span!("root");
+            let span2 = tracing::info_span!(parent: &span1, "handle_order");
+            let span3 = tracing::info_span!(parent: &span2, "db_query");
+
+            let _s1 = span1.enter();
+            let _s2 = span2.enter();
+            let _s3 = span3.enter();
+
+            info!("Test")
+        };
+
+        let data = capture(
+            SolinkJsonFormat::new()
+                .with_timestamp(false)
+                .with_target(false)
+                .with_span_path(Some(SpanPath::Joined(">".into()))),
+            log,
+        );
+        assert_eq!(
+            data.trim(),
+            r#"{"level":"INFO","message":"Test","span":"db_query","span_path":"root>handle_order>db_query"}"#,
+        );
+
+        let data = capture(
+            SolinkJsonFormat::new()
+                .with_span_path(Some(SpanPath::Array))
+                .with_span_id(true)
+                .with_parent_span_id(true)
+                .with_root_span_id(true),
+            log,
+        );
+        let line: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(
+            line["span_path"],
+            serde_json::json!(["root", "handle_order", "db_query"])
+        );
+        let ids = [
+            line["span_id"].as_u64().unwrap(),
+            line["parent_span_id"].as_u64().unwrap(),
+            line["root_span_id"].as_u64().unwrap(),
+        ];
+        assert!(ids[0] != ids[1] && ids[1] != ids[2] && ids[0] != ids[2]);
+    }
+
+    #[tokio::test]
+    async fn should_write_metadata_fields() {
+        let format = SolinkJsonFormat::new()
+            .with_timestamp(false)
+            .with_target(false)
+            .with_module_path(true)
+            .with_file(true)
+            .with_line_number(true)
+            .with_thread_name(true)
+            .with_thread_id(true)
+            .with_pid(true)
+            .with_hostname(true)
+            .with_key(Builtin::File, "src_file")
+            .with_key(Builtin::Line, "src_line");
+
+        let mut line = 0;
+        let data = capture(format, || {
+            line = line!() + 1;
+            info!("Test")
+        });
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        let thread = std::thread::current();
+
+        assert_eq!(data["module_path"], "solink_tracing_flat_json::tests");
+        assert_eq!(data["src_file"], file!());
+        assert_eq!(data["src_line"], line);
+        assert_eq!(data["thread_name"], thread.name().unwrap());
+        assert_eq!(data["thread_id"], format!("{:?}", thread.id()));
+        assert_eq!(data["pid"], std::process::id());
+        assert!(data["hostname"].is_string());
+        assert!(data.get("file").is_none());
+    }
+
+    #[tokio::test]
+    async fn should_write_timestamps_from_the_clock_or_a_timer() {
+        let clock = Clock::fixed("2024-06-18T21:17:44.902137Z".parse().unwrap());
+        let format = SolinkJsonFormat::new()
+            .with_target(false)
+            .with_timestamp_format(TimestampFormat::UnixMillis)
+            .with_clock(clock);
+
+        let data = capture(format, || info!("Test"));
+        assert_eq!(
+            data.trim(),
+            r#"{"timestamp":1718745464902,"level":"INFO","message":"Test"}"#,
+        );
+
+        struct Never;
+        impl FormatTime for Never {
+            fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+                w.write_str("never")
+            }
+        }
+
+        let format = SolinkJsonFormat::new().with_target(false).with_timer(Never);
+        let data = capture(format, || info!("Test"));
+        assert_eq!(
+            data.trim(),
+            r#"{"timestamp":"never","level":"INFO","message":"Test"}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn should_rename_builtin_event_and_span_fields() {
+        let format = SolinkJsonFormat::new()
+            .with_clock(Clock::fixed("2024-06-18T21:17:44Z".parse().unwrap()))
+            .with_timestamp_format(TimestampFormat::Rfc3339(SecondsFormat::Secs))
+            .with_renames([
+                ("timestamp", "@timestamp"),
+                ("level", "severity"),
+                ("target", "logger"),
+                ("message", "msg"),
+                ("user_id", "user.id"),
+            ]);
+
+        let data = capture(format, || {
+            let span = tracing::info_span!("parent", user_id = 7);
+            let _s = span.enter();
+
+            info!(severity = "high", "Test")
+        });
+        assert_eq!(
+            data.trim(),
+            r#"{"@timestamp":"2024-06-18T21:17:44Z","severity":"INFO","logger":"solink_tracing_flat_json::tests","msg":"Test","fields.severity":"high","span":"parent","user.id":7}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn should_write_static_fields() {
+        std::env::set_var("SOLINK_TEST_REGION", "ca-central-1");
+        let format = SolinkJsonFormat::new()
+            .with_timestamp(false)
+            .with_target(false)
+            .with_static_field("service", "orders")
+            .with_static_field("version", serde_json::json!({ "major": 1, "minor": 2 }))
+            .with_env_field("region", "SOLINK_TEST_REGION")
+            .with_env_field("zone", "SOLINK_TEST_UNSET");
+
+        let data = capture(format, || info!(service = "other", "Test"));
+        assert_eq!(
+            data.trim(),
+            r#"{"level":"INFO","service":"orders","version":{"major":1,"minor":2},"region":"ca-central-1","message":"Test","fields.service":"other"}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn should_rename_static_fields_that_clash_with_built_in_fields() {
+        let format = SolinkJsonFormat::new()
+            .with_timestamp(false)
+            .with_target(false)
+            .with_static_field("span", "svc")
+            .with_span_path(Some(SpanPath::Array))
+            .with_static_field("span_path", "/")
+            .with_static_field("service", "orders");
+
+        let data = capture(format, || {
+            let span = tracing::info_span!("handle");
+            let _s = span.enter();
+
+            info!(span = "other", "Test")
+        });
+        assert_eq!(
+            data.trim(),
+            r#"{"level":"INFO","static.span":"svc","static.span_path":"/","service":"orders","message":"Test","fields.span":"other","span":"handle","span_path":["handle"]}"#,
+        );
+    }
+
+    #[test]
+    fn should_fail_to_add_a_static_field_that_is_not_json() {
+        let value = std::collections::HashMap::from([((1, 2), "x")]);
+        let result = SolinkJsonFormat::new().try_with_static_field("key", value);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "redact")]
+    async fn should_redact_event_and_span_fields() {
+        let format = SolinkJsonFormat::new()
+            .with_timestamp(false)
+            .with_target(false)
+            .with_redactor(
+                Redactor::new()
+                    .redact_key_ignore_case("password")
+                    .scan_values(ValueScanner::email()),
+            );
+
+        let data = capture(format, || {
+            let span = tracing::info_span!("login", Password = "hunter2");
+            let _s = span.enter();
+
+            info!(user = "bob@example.com", "Logged in")
+        });
+        assert_eq!(
+            data.trim(),
+            r#"{"level":"INFO","message":"Logged in","user":"[REDACTED]","span":"login","Password":"[REDACTED]"}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn should_lay_out_fields() {
+        let log = || {
+            let span1 = tracing::info_span!("parent", x = 7);
+            let span2 = tracing::info_span!(parent: &span1, "child", y = 9, x = 8);
+
+            let _s1 = span1.enter();
+            let _s2 = span2.enter();
+
+            info!(z = 10, "Test")
+        };
+        let format = |layout| {
+            SolinkJsonFormat::new()
+                .with_timestamp(false)
+                .with_target(false)
+                .with_layout(layout)
+        };
+
+        let cases = [
+            (
+                Layout::PrefixedFlat,
+                r#"{"level":"INFO","message":"Test","z":10,"span":"child","child.x":8,"child.y":9,"parent.x":7}"#,
+            ),
+            (
+                Layout::NestedBySpan,
+                r#"{"level":"INFO","message":"Test","z":10,"span":"child","spans":{"parent":{"x":7},"child":{"x":8,"y":9}}}"#,
+            ),
+            (
+                Layout::FieldsObject,
+                r#"{"level":"INFO","fields":{"message":"Test","z":10},"span":"child","x":8,"y":9}"#,
+            ),
+            (
+                Layout::AllFieldsObject,
+                r#"{"level":"INFO","message":"Test","fields":{"z":10,"x":8,"y":9},"span":"child"}"#,
+            ),
+        ];
+        for (layout, expected) in cases {
+            assert_eq!(capture(format(layout), log).trim(), expected);
+        }
     }
 }
@@ -0,0 +1,568 @@
+//! A sibling of `SolinkJsonFormat` that writes logfmt instead of JSON.
+
+use std::fmt;
+
+use serde::ser::{self, Error as _, Serialize, Serializer};
+use serde_json::Value;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+use crate::{insert_formatted_fields, write_str, Encoding, SolinkJsonFormat};
+
+/// `FormatEvent` for logfmt, with the same fields as [`SolinkJsonFormat`]:
+///
+/// ```txt
+/// timestamp=2024-06-18T21:17:44.902137000Z level=INFO target=app message="Hello world" x=7
+/// ```
+///
+/// Fields are collected, ordered, renamed and redacted exactly as configured
+/// on the `SolinkJsonFormat` it is built from:
+///
+/// ```rs
+///     let format = SolinkLogfmtFormat::new()
+///         .with_format(SolinkJsonFormat::new().with_target(false));
+///     tracing_subscriber::fmt()
+///         .fmt_fields(JsonFields::new())
+///         .event_format(format)
+///         .init();
+/// ```
+///
+/// Objects are flattened into dotted keys (`spans.parent.x=7`), and arrays
+/// are written as quoted JSON. Values are quoted when they are empty or
+/// contain spaces, `=`, `"` or control characters, and keys have those
+/// characters replaced with `_`.
+#[derive(Default)]
+pub struct SolinkLogfmtFormat {
+    format: SolinkJsonFormat,
+}
+
+impl SolinkLogfmtFormat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the options fields are collected with.
+    pub fn with_format(mut self, format: SolinkJsonFormat) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl From<SolinkJsonFormat> for SolinkLogfmtFormat {
+    fn from(format: SolinkJsonFormat) -> Self {
+        Self { format }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for SolinkLogfmtFormat
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        self.format.write_event(
+            event,
+            ctx.event_scope(),
+            insert_formatted_fields::<N, S>,
+            |fields, buffer| Encoding::Logfmt.encode_fields(fields, buffer),
+            |line| write_str(&mut writer, line),
+        )
+    }
+}
+
+/// Append `value`, which must serialize as a map, to `out` as logfmt pairs,
+/// with keys in italic cyan and `=` dimmed if `ansi` is set.
+pub(crate) fn to_writer(
+    out: &mut Vec<u8>,
+    value: &impl Serialize,
+    ansi: bool,
+) -> Result<(), Error> {
+    value.serialize(ValueSerializer {
+        out,
+        key: None,
+        ansi,
+    })
+}
+
+type Error = serde_json::Error;
+
+/// Writes a value as one or more `key=value` pairs. At the top level, with
+/// no key, only maps can be written.
+struct ValueSerializer<'b> {
+    out: &'b mut Vec<u8>,
+    key: Option<String>,
+    ansi: bool,
+}
+
+impl ValueSerializer<'_> {
+    /// Write `key=value`, quoting the value if needed when `quote` is set.
+    fn write(self, value: &str, quote: bool) -> Result<(), Error> {
+        let key = self
+            .key
+            .ok_or_else(|| Error::custom("a logfmt line must be a map"))?;
+        if !self.out.is_empty() {
+            self.out.push(b' ');
+        }
+        if self.ansi {
+            self.out.extend_from_slice(b"\x1b[3;36m");
+        }
+        for c in key.chars() {
+            let c = if needs_quotes(c) { '_' } else { c };
+            self.out
+                .extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        if self.ansi {
+            self.out.extend_from_slice(b"\x1b[0m\x1b[2m=\x1b[0m");
+        } else {
+            self.out.push(b'=');
+        }
+        if quote && (value.is_empty() || value.chars().any(needs_quotes)) {
+            // JSON string escapes are what logfmt parsers expect.
+            serde_json::to_writer(&mut *self.out, value)?;
+        } else {
+            self.out.extend_from_slice(value.as_bytes());
+        }
+        Ok(())
+    }
+
+    /// Write a value that has no logfmt representation as quoted JSON.
+    fn write_json(self, value: &impl Serialize) -> Result<(), Error> {
+        let json = serde_json::to_string(value)?;
+        self.write(&json, true)
+    }
+
+    fn nested(&mut self, key: &str) -> ValueSerializer<'_> {
+        let key = match &self.key {
+            Some(prefix) => format!("{}.{}", prefix, key),
+            None => key.to_string(),
+        };
+        ValueSerializer {
+            out: self.out,
+            key: Some(key),
+            ansi: self.ansi,
+        }
+    }
+}
+
+fn needs_quotes(c: char) -> bool {
+    c <= ' ' || c == '=' || c == '"' || c.is_control()
+}
+
+impl<'b> Serializer for ValueSerializer<'b> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = SeqSerializer<'b>;
+    type SerializeTuple = SeqSerializer<'b>;
+    type SerializeTupleStruct = SeqSerializer<'b>;
+    type SerializeTupleVariant = SeqSerializer<'b>;
+    type SerializeMap = MapSerializer<'b>;
+    type SerializeStruct = MapSerializer<'b>;
+    type SerializeStructVariant = MapSerializer<'b>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.write(if v { "true" } else { "false" }, false)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.write(&v.to_string(), false)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.write(&v.to_string(), false)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Error> {
+        self.write(&v.to_string(), false)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Error> {
+        self.write(&v.to_string(), false)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.write(&v.to_string(), false)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.write(v.encode_utf8(&mut [0; 4]), true)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.write(v, true)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.write_json(&v)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.write("null", false)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        mut self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self.nested(variant))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer<'b>, Error> {
+        Ok(SeqSerializer {
+            values: Vec::with_capacity(len.unwrap_or_default()),
+            inner: self,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer<'b>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer<'b>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer<'b>, Error> {
+        let key = Some(match &self.key {
+            Some(key) => format!("{}.{}", key, variant),
+            None => variant.to_string(),
+        });
+        ValueSerializer { key, ..self }.serialize_seq(Some(len))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer<'b>, Error> {
+        Ok(MapSerializer {
+            inner: self,
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<MapSerializer<'b>, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<MapSerializer<'b>, Error> {
+        let key = Some(match &self.key {
+            Some(key) => format!("{}.{}", key, variant),
+            None => variant.to_string(),
+        });
+        ValueSerializer { key, ..self }.serialize_map(Some(len))
+    }
+}
+
+/// Collects the items of a sequence, to write them as quoted JSON.
+struct SeqSerializer<'b> {
+    inner: ValueSerializer<'b>,
+    values: Vec<Value>,
+}
+
+impl SeqSerializer<'_> {
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.values.push(serde_json::to_value(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        self.inner.write_json(&self.values)
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+/// Writes each entry of a map as a pair, prefixing keys with the map's own
+/// key when nested.
+struct MapSerializer<'b> {
+    inner: ValueSerializer<'b>,
+    key: Option<String>,
+}
+
+impl ser::SerializeMap for MapSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(match serde_json::to_value(key)? {
+            Value::String(key) => key,
+            key => key.to_string(),
+        });
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().expect("serialize_key is called first");
+        value.serialize(self.inner.nested(&key))
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self.inner.nested(key))
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for MapSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self.inner.nested(key))
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tracing::{dispatcher, info};
+    use tracing_subscriber::{fmt::format::JsonFields, layer::SubscriberExt, Registry};
+
+    use super::*;
+    #[cfg(feature = "redact")]
+    use crate::Redactor;
+    use crate::{test_support::TestWriter, Layout};
+
+    fn capture(format: SolinkJsonFormat, f: impl FnOnce()) -> String {
+        let writer = TestWriter::new();
+        let layer = {
+            let writer = writer.clone();
+            tracing_subscriber::fmt::layer()
+                .event_format(SolinkLogfmtFormat::from(format))
+                .fmt_fields(JsonFields::new())
+                .with_writer(move || writer.clone())
+        };
+
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, f);
+
+        writer.contents()
+    }
+
+    #[test]
+    fn should_write_logfmt_with_the_same_fields() {
+        let log = || {
+            let span = tracing::info_span!("handle", user = "bob", tags = ?["a", "b"]);
+            let _s = span.enter();
+
+            info!(
+                quote = "say \"hi\"",
+                empty = "",
+                path = "/a=b",
+                x = 1.5,
+                "Hello\nworld"
+            )
+        };
+        let format = || {
+            SolinkJsonFormat::new()
+                .with_timestamp(false)
+                .with_target(false)
+        };
+
+        assert_eq!(
+            capture(format(), log),
+            concat!(
+                r#"level=INFO message="Hello\nworld" quote="say \"hi\"" empty="" path="/a=b" x=1.5 "#,
+                r#"span=handle tags="[\"a\", \"b\"]" user=bob"#,
+                "\n",
+            ),
+        );
+        assert_eq!(
+            capture(format().with_layout(Layout::NestedBySpan), || {
+                let span = tracing::info_span!("handle", x = 7);
+                let _s = span.enter();
+                info!("Test")
+            }),
+            "level=INFO message=Test span=handle spans.handle.x=7\n",
+        );
+    }
+
+    #[cfg(feature = "redact")]
+    #[test]
+    fn should_redact_like_json() {
+        let format = SolinkJsonFormat::new()
+            .with_timestamp(false)
+            .with_target(false)
+            .with_redactor(Redactor::new().redact_key("user"));
+
+        assert_eq!(
+            capture(format, || {
+                let span = tracing::info_span!("handle", user = "bob");
+                let _s = span.enter();
+                info!("Test")
+            }),
+            "level=INFO message=Test span=handle user=[REDACTED]\n",
+        );
+    }
+
+    fn line(value: Value) -> String {
+        let mut out = Vec::new();
+        to_writer(&mut out, &value, false).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn should_quote_values_and_sanitize_keys() {
+        assert_eq!(line(json!({"my key": "a b"})), r#"my_key="a b""#);
+        assert_eq!(
+            line(json!({"list": [1, {"a": 2}]})),
+            r#"list="[1,{\"a\":2}]""#
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Trace correlation ids read from the OpenTelemetry context.
+
+use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+use serde_json::Value;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+/// How to write the trace and span ids taken from OpenTelemetry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtelIds {
+    /// As W3C trace context ids, under `trace_id` and `span_id`: 32 and 16
+    /// lowercase hex digits.
+    W3c,
+    /// As Datadog ids, under `dd.trace_id` and `dd.span_id`: the lower 64
+    /// bits of the trace id and the span id, as decimal strings.
+    Datadog,
+}
+
+impl OtelIds {
+    /// The trace and span ids `tracing-opentelemetry` assigned to `span`, if
+    /// it is part of a valid trace.
+    pub(crate) fn read<'a, R: LookupSpan<'a>>(span: &SpanRef<'a, R>) -> Option<(TraceId, SpanId)> {
+        let extensions = span.extensions();
+        let otel = extensions.get::<OtelData>()?;
+
+        let trace_id = otel
+            .builder
+            .trace_id
+            .unwrap_or_else(|| otel.parent_cx.span().span_context().trace_id());
+        let span_id = otel.builder.span_id?;
+        (trace_id != TraceId::INVALID && span_id != SpanId::INVALID).then_some((trace_id, span_id))
+    }
+
+    pub(crate) fn trace_id(self, prefix: &str, trace_id: TraceId) -> Value {
+        match self {
+            OtelIds::W3c => format!("{}{}", prefix, trace_id),
+            OtelIds::Datadog => {
+                let lower = u128::from_be_bytes(trace_id.to_bytes()) as u64;
+                format!("{}{}", prefix, lower)
+            }
+        }
+        .into()
+    }
+
+    pub(crate) fn span_id(self, span_id: SpanId) -> Value {
+        match self {
+            OtelIds::W3c => span_id.to_string(),
+            OtelIds::Datadog => u64::from_be_bytes(span_id.to_bytes()).to_string(),
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{SpanContext, TracerProvider as _};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use serde_json::json;
+    use tracing::{dispatcher, info};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{test_support::TestWriter, FlatJsonLayer, SolinkJsonFormat};
+
+    /// Log an event in a span of a trace, returning the fields with `_id` in
+    /// their name, and the span's context.
+    fn log(format: SolinkJsonFormat) -> (Value, SpanContext) {
+        let writer = TestWriter::new();
+        // The tracer only holds a weak reference to its provider.
+        let provider = TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber = Registry::default()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .with(
+                FlatJsonLayer::new()
+                    .with_format(format.with_timestamp(false).with_target(false))
+                    .with_writer(writer.clone()),
+            );
+
+        let mut context = None;
+        dispatcher::with_default(&dispatcher::Dispatch::new(subscriber), || {
+            let span1 = tracing::info_span!("parent");
+            let span2 = tracing::info_span!(parent: &span1, "child");
+            let _s1 = span1.enter();
+            let _s2 = span2.enter();
+            context = Some(span2.context().span().span_context().clone());
+
+            info!("Test")
+        });
+
+        let mut data: serde_json::Map<String, Value> =
+            serde_json::from_str(&writer.contents()).unwrap();
+        data.retain(|key, _| key.contains("_id") || key.contains("trace"));
+        (Value::Object(data), context.unwrap())
+    }
+
+    #[test]
+    fn should_write_opentelemetry_ids() {
+        for ids in [OtelIds::W3c, OtelIds::Datadog] {
+            let (data, context) = log(SolinkJsonFormat::new().with_opentelemetry_ids(ids));
+
+            let (trace_id, span_id) = (context.trace_id(), context.span_id());
+            let expected = match ids {
+                OtelIds::W3c => json!({
+                    "trace_id": trace_id.to_string(),
+                    "span_id": span_id.to_string(),
+                }),
+                OtelIds::Datadog => json!({
+                    "dd.trace_id": (u128::from_be_bytes(trace_id.to_bytes()) as u64).to_string(),
+                    "dd.span_id": u64::from_be_bytes(span_id.to_bytes()).to_string(),
+                }),
+            };
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[test]
+    fn should_keep_the_trace_id_off_if_it_was_turned_off() {
+        // As `gcp` does without a project id.
+        let format = SolinkJsonFormat::new()
+            .with_trace_id(None::<String>)
+            .with_opentelemetry_ids(OtelIds::W3c);
+        let (data, context) = log(format);
+
+        assert_eq!(data, json!({ "span_id": context.span_id().to_string() }));
+    }
+}
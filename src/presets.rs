@@ -0,0 +1,303 @@
+//! Formats preconfigured for specific log ingestion services.
+
+use chrono::SecondsFormat;
+
+use crate::{Builtin, IdFormat, Layout, LevelFormat, SolinkJsonFormat, TimestampFormat};
+
+/// The version of the Elastic Common Schema written by
+/// [`SolinkJsonFormat::ecs`].
+pub const ECS_VERSION: &str = "8.11.0";
+
+/// Where [`SolinkJsonFormat::ecs`] writes event and span fields.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EcsFields {
+    /// At the top level, next to the ECS fields. Use this if your fields are
+    /// named after ECS fields, or your index maps them. This is the default.
+    #[default]
+    TopLevel,
+    /// Under `labels`, so they can't conflict with ECS fields. As labels are
+    /// keywords, numbers and booleans are written as strings, and arrays,
+    /// objects and nulls are left out.
+    Labels,
+}
+
+impl SolinkJsonFormat {
+    /// A format for Google Cloud Logging's structured logging, as read from
+    /// stdout on GKE, Cloud Run and similar:
+    ///
+    /// ```txt
+    /// {"time":"2024-06-18T21:17:44.902137000Z","severity":"WARNING","target":"app",
+    ///  "logging.googleapis.com/sourceLocation":{"file":"src/main.rs","line":"42","function":"app"},
+    ///  "logging.googleapis.com/trace":"projects/my-project/traces/4bf92f3577b34da6a3ce929d0e0e4736",
+    ///  "logging.googleapis.com/spanId":"00f067aa0ba902b7","message":"Hello","x":7}
+    /// ```
+    ///
+    /// The trace and span id are only written for events in a real trace:
+    /// both come from the OpenTelemetry context with
+    /// `with_opentelemetry_ids(OtelIds::W3c)`, or the trace alone from a
+    /// field set with [`with_trace_id_field`](Self::with_trace_id_field),
+    /// such as the trace id of the `X-Cloud-Trace-Context` header. The trace
+    /// is also only written if a project id is given, or found in the
+    /// `GOOGLE_CLOUD_PROJECT` environment variable, since Cloud Logging
+    /// expects the trace's full resource name.
+    ///
+    /// Event and span fields are flattened as usual, and every option can
+    /// still be changed afterwards.
+    pub fn gcp(project_id: Option<&str>) -> Self {
+        let project_id = project_id
+            .map(str::to_string)
+            .or_else(|| std::env::var("GOOGLE_CLOUD_PROJECT").ok());
+
+        Self::new()
+            .with_key(Builtin::Timestamp, "time")
+            .with_key(Builtin::Level, "severity")
+            .with_level_format(LevelFormat::Gcp)
+            .with_source_location(true)
+            .with_key(
+                Builtin::SourceLocation,
+                "logging.googleapis.com/sourceLocation",
+            )
+            .with_trace_id(project_id.map(|project_id| format!("projects/{}/traces/", project_id)))
+            .with_key(Builtin::TraceId, "logging.googleapis.com/trace")
+            .with_id_format(IdFormat::Hex)
+            .with_key(Builtin::SpanId, "logging.googleapis.com/spanId")
+    }
+}
+
+impl SolinkJsonFormat {
+    /// A format that follows the Elastic Common Schema, for Elasticsearch
+    /// indices mapped with ECS:
+    ///
+    /// ```txt
+    /// {"@timestamp":"2024-06-18T21:17:44.902Z","log.level":"warn","log.logger":"app",
+    ///  "log.origin.file.name":"src/main.rs","log.origin.file.line":42,"process.thread.name":"main",
+    ///  "process.pid":1,"host.hostname":"web-1","ecs.version":"8.11.0","message":"Hello",
+    ///  "trace.id":"4bf92f3577b34da6a3ce929d0e0e4736","span.id":"00f067aa0ba902b7","x":7}
+    /// ```
+    ///
+    /// ECS has no field for the span name or the module path, so they are
+    /// left out. `trace.id` and `span.id` are only written for events in a
+    /// real trace: both come from the OpenTelemetry context with
+    /// `with_opentelemetry_ids(OtelIds::W3c)`, or the trace id alone from a
+    /// field set with [`with_trace_id_field`](Self::with_trace_id_field).
+    /// Every option can still be changed afterwards.
+    pub fn ecs(fields: EcsFields) -> Self {
+        let format = Self::new()
+            .with_timestamp_format(TimestampFormat::Rfc3339(SecondsFormat::Millis))
+            .with_key(Builtin::Timestamp, "@timestamp")
+            .with_key(Builtin::Level, "log.level")
+            .with_level_format(LevelFormat::Lowercase)
+            .with_key(Builtin::Target, "log.logger")
+            .with_file(true)
+            .with_key(Builtin::File, "log.origin.file.name")
+            .with_line_number(true)
+            .with_key(Builtin::Line, "log.origin.file.line")
+            .with_thread_name(true)
+            .with_key(Builtin::ThreadName, "process.thread.name")
+            .with_pid(true)
+            .with_key(Builtin::Pid, "process.pid")
+            .with_hostname(true)
+            .with_key(Builtin::Hostname, "host.hostname")
+            .with_static_field("ecs.version", ECS_VERSION)
+            .with_span_name(false)
+            .with_key(Builtin::SpanId, "span.id")
+            .with_trace_id(Some(""))
+            .with_key(Builtin::TraceId, "trace.id");
+
+        match fields {
+            EcsFields::TopLevel => format,
+            EcsFields::Labels => Self {
+                keyword_fields: true,
+                ..format
+                    .with_layout(Layout::AllFieldsObject)
+                    .with_key(Builtin::Fields, "labels")
+            },
+        }
+    }
+}
+
+impl SolinkJsonFormat {
+    /// A format the `bunyan` CLI and other Node.js tooling can read, with
+    /// `name` set to `name`:
+    ///
+    /// ```txt
+    /// {"time":"2024-06-18T21:17:44.902Z","level":40,"target":"app","pid":1,
+    ///  "hostname":"web-1","v":0,"name":"app","msg":"Hello","span":"handle","x":7}
+    /// ```
+    ///
+    /// Events without a message get an empty `msg`, since Bunyan requires
+    /// one. Event and span fields are flattened as usual, and every option
+    /// can still be changed afterwards.
+    pub fn bunyan(name: impl Into<String>) -> Self {
+        Self::new()
+            .with_key(Builtin::Timestamp, "time")
+            .with_timestamp_format(TimestampFormat::Rfc3339(SecondsFormat::Millis))
+            .with_level_format(LevelFormat::Bunyan)
+            .with_pid(true)
+            .with_hostname(true)
+            .with_static_field("v", 0)
+            .with_static_field("name", name.into())
+            .with_rename("message", "msg")
+            .with_required_message(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map, Value};
+    use tracing::{dispatcher, warn};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{test_support::TestWriter, Clock, FlatJsonLayer};
+
+    fn capture(format: SolinkJsonFormat, f: impl FnOnce()) -> Value {
+        let writer = TestWriter::new();
+        let layer = FlatJsonLayer::new()
+            .with_format(format.with_clock(Clock::fixed("2024-06-18T21:17:44Z".parse().unwrap())))
+            .with_writer(writer.clone());
+
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, f);
+
+        serde_json::from_str(&writer.contents()).unwrap()
+    }
+
+    #[test]
+    fn should_write_gcp_structured_logs() {
+        let mut line = 0;
+        let log = || {
+            let span = tracing::info_span!("handle", x = 7);
+            let _s = span.enter();
+
+            line = line!() + 1;
+            warn!("Hello")
+        };
+        let data = capture(SolinkJsonFormat::gcp(Some("my-project")), log);
+
+        assert_eq!(
+            data,
+            json!({
+                "time": "2024-06-18T21:17:44.000000000Z",
+                "severity": "WARNING",
+                "target": "solink_tracing_flat_json::presets::tests",
+                "logging.googleapis.com/sourceLocation": {
+                    "file": file!(),
+                    "line": line.to_string(),
+                    "function": "solink_tracing_flat_json::presets::tests",
+                },
+                "message": "Hello",
+                "span": "handle",
+                "x": 7,
+            })
+        );
+    }
+
+    #[test]
+    fn should_read_the_gcp_trace_from_a_field() {
+        let format = SolinkJsonFormat::gcp(Some("my-project")).with_trace_id_field("trace_id");
+        let data = capture(format, || {
+            let span = tracing::info_span!("handle", trace_id = "4bf92f3577b34da6a3ce929d0e0e4736");
+            let _s = span.enter();
+            warn!("Hello")
+        });
+
+        assert_eq!(
+            data["logging.googleapis.com/trace"],
+            "projects/my-project/traces/4bf92f3577b34da6a3ce929d0e0e4736",
+        );
+        assert_eq!(data["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(data.get("logging.googleapis.com/spanId").is_none());
+    }
+
+    /// The ECS fields the preset may write, from
+    /// https://www.elastic.co/guide/en/ecs/current/ecs-field-reference.html.
+    const ECS_FIELDS: [(&str, &str); 13] = [
+        ("@timestamp", "date"),
+        ("ecs.version", "keyword"),
+        ("host.hostname", "keyword"),
+        ("labels", "object"),
+        ("log.level", "keyword"),
+        ("log.logger", "keyword"),
+        ("log.origin.file.line", "long"),
+        ("log.origin.file.name", "keyword"),
+        ("message", "match_only_text"),
+        ("process.pid", "long"),
+        ("process.thread.name", "keyword"),
+        ("span.id", "keyword"),
+        ("trace.id", "keyword"),
+    ];
+
+    /// Check each field is in the ECS field set, with a value of its type.
+    fn assert_ecs_fields(data: &Map<String, Value>) {
+        for (key, value) in data {
+            let Some((_, ty)) = ECS_FIELDS.iter().find(|(field, _)| field == key) else {
+                panic!("{} is not an ECS field", key);
+            };
+            let valid = match *ty {
+                "long" => value.is_u64(),
+                "object" => value.is_object(),
+                "date" => chrono::DateTime::parse_from_rfc3339(value.as_str().unwrap()).is_ok(),
+                _ => value.is_string(),
+            };
+            assert!(valid, "{} is not a valid {}: {}", key, ty, value);
+        }
+    }
+
+    #[test]
+    fn should_write_ecs_fields() {
+        let log = || {
+            let span = tracing::info_span!("handle", x = 7, ok = true);
+            let _s = span.enter();
+            warn!(y = "z", "Hello")
+        };
+
+        let data = capture(SolinkJsonFormat::ecs(EcsFields::Labels), log);
+        let data = data.as_object().unwrap();
+        assert_ecs_fields(data);
+        assert_eq!(data["log.level"], "warn");
+        assert_eq!(data["ecs.version"], ECS_VERSION);
+        assert_eq!(data["message"], "Hello");
+        assert_eq!(data["labels"], json!({"y": "z", "x": "7", "ok": "true"}));
+        assert!(data.get("trace.id").is_none() && data.get("span.id").is_none());
+    }
+
+    #[test]
+    fn should_write_ecs_fields_at_the_top_level() {
+        let format = SolinkJsonFormat::ecs(EcsFields::TopLevel).with_trace_id_field("trace_id");
+        let data = capture(format, || {
+            let span = tracing::info_span!("handle", trace_id = "4bf92f3577b34da6a3ce929d0e0e4736");
+            let _s = span.enter();
+            warn!(y = "z", "Hello")
+        });
+        let mut data = data.as_object().unwrap().clone();
+
+        // Only the event and span fields are not ECS fields.
+        assert_eq!(data.remove("y"), Some(json!("z")));
+        assert!(data.remove("trace_id").is_some());
+        assert_ecs_fields(&data);
+        assert_eq!(data["trace.id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    }
+
+    #[test]
+    fn should_write_bunyan_records() {
+        let data = capture(SolinkJsonFormat::bunyan("app"), || {
+            let span = tracing::info_span!("handle", x = 7);
+            let _s = span.enter();
+            warn!("Hello");
+        });
+
+        // The fields the `bunyan` CLI requires of every record.
+        assert_eq!(data["v"], 0);
+        assert_eq!(data["level"], 40);
+        assert_eq!(data["name"], "app");
+        assert!(data["hostname"].is_string());
+        assert_eq!(data["pid"], std::process::id());
+        assert_eq!(data["time"], "2024-06-18T21:17:44.000Z");
+        assert_eq!(data["msg"], "Hello");
+        assert_eq!(data["x"], 7);
+
+        let data = capture(SolinkJsonFormat::bunyan("app"), || tracing::debug!(x = 7));
+        assert_eq!((&data["level"], &data["msg"]), (&json!(20), &json!("")));
+    }
+}
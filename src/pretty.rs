@@ -0,0 +1,303 @@
+//! A human-readable formatter for local development, and a switch between it
+//! and JSON driven by the environment.
+
+use std::{fmt, io::IsTerminal};
+
+use serde_json::Value;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+use crate::{
+    fields::FlatFields, insert_formatted_fields, logfmt, write_str, Builtin, FormatError,
+    SolinkJsonFormat, SolinkLogfmtFormat,
+};
+
+/// `FormatEvent` for reading logs on a terminal, with the same fields as
+/// [`SolinkJsonFormat`]:
+///
+/// ```txt
+/// 2024-06-18T21:17:44.902137000Z  INFO app::orders: Order placed order_id=42 span=handle user="bob smith"
+/// ```
+///
+/// The timestamp, level, target and message come first, followed by the
+/// remaining fields as `key=value` pairs: event fields first, then span
+/// fields, innermost span first. Levels are colored and keys are in italic
+/// cyan, unless ANSI colors are turned off.
+pub struct SolinkPrettyFormat {
+    format: SolinkJsonFormat,
+    ansi: bool,
+}
+
+impl SolinkPrettyFormat {
+    pub fn new() -> Self {
+        Self {
+            format: SolinkJsonFormat::new(),
+            ansi: true,
+        }
+    }
+
+    /// Set the options fields are collected with.
+    pub fn with_format(mut self, format: SolinkJsonFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set whether to use ANSI colors. On by default.
+    pub fn with_ansi(mut self, ansi: bool) -> Self {
+        self.ansi = ansi;
+        self
+    }
+
+    fn encode(
+        &self,
+        level: &Level,
+        fields: &FlatFields,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), FormatError> {
+        let keys = [
+            self.format.keys.get(Builtin::Timestamp),
+            self.format.keys.get(Builtin::Level),
+            self.format.keys.get(Builtin::Target),
+            self.format.message_key(),
+        ];
+        let [timestamp, level_value, target, message] = keys.map(|key| fields.find(key));
+        let text = |value: &Value| match value {
+            Value::String(text) => text.clone(),
+            value => value.to_string(),
+        };
+
+        let mut header = String::new();
+        if let Some((_, timestamp)) = timestamp {
+            header.push_str(&self.paint("2", &text(timestamp)));
+            header.push(' ');
+        }
+        if let Some((_, level_value)) = level_value {
+            let color = match *level {
+                Level::TRACE => "35",
+                Level::DEBUG => "34",
+                Level::INFO => "32",
+                Level::WARN => "33",
+                Level::ERROR => "31",
+            };
+            header.push_str(&self.paint(color, &format!("{:>5}", text(level_value))));
+            header.push(' ');
+        }
+        if let Some((_, target)) = target {
+            header.push_str(&self.paint("2", &format!("{}:", text(target))));
+            header.push(' ');
+        }
+        if let Some((_, message)) = message {
+            header.push_str(&text(message));
+        }
+        buffer.extend_from_slice(header.trim_end().as_bytes());
+
+        let skip: Vec<usize> = [timestamp, level_value, target, message]
+            .into_iter()
+            .flatten()
+            .map(|(index, _)| index)
+            .collect();
+        logfmt::to_writer(
+            buffer,
+            &fields.serializable_without(false, &skip),
+            self.ansi,
+        )
+        .map_err(FormatError::Serialize)?;
+        buffer.push(b'\n');
+        Ok(())
+    }
+
+    /// `text` in the given SGR style, if ANSI colors are on.
+    fn paint(&self, style: &str, text: &str) -> String {
+        match self.ansi {
+            true => format!("\x1b[{}m{}\x1b[0m", style, text),
+            false => text.to_string(),
+        }
+    }
+}
+
+impl Default for SolinkPrettyFormat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<SolinkJsonFormat> for SolinkPrettyFormat {
+    fn from(format: SolinkJsonFormat) -> Self {
+        Self::new().with_format(format)
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for SolinkPrettyFormat
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let level = event.metadata().level();
+        self.format.write_event(
+            event,
+            ctx.event_scope(),
+            insert_formatted_fields::<N, S>,
+            |fields, buffer| self.encode(level, fields, buffer),
+            |line| write_str(&mut writer, line),
+        )
+    }
+}
+
+/// `FormatEvent` that writes JSON, logfmt or human-readable text, as chosen
+/// by an environment variable, so the same setup can log JSON in production
+/// and readable text on a developer's terminal:
+///
+/// ```rs
+///     tracing_subscriber::fmt()
+///         .fmt_fields(JsonFields::new())
+///         .event_format(SolinkFormat::from_env("LOG_FORMAT", SolinkJsonFormat::new()))
+///         .init();
+/// ```
+pub enum SolinkFormat {
+    Json(SolinkJsonFormat),
+    Logfmt(SolinkLogfmtFormat),
+    Pretty(SolinkPrettyFormat),
+}
+
+impl SolinkFormat {
+    /// Choose the format from the environment variable `var`: `json`,
+    /// `logfmt` or `pretty`. If it is not set, or set to anything else, the
+    /// format is `pretty` when stdout is a terminal and `json` otherwise.
+    ///
+    /// Fields are collected as configured on `format`. Pretty output is only
+    /// colored when stdout is a terminal and `NO_COLOR` is not set.
+    ///
+    /// Only stdout is checked, so for a writer other than stdout, use
+    /// [`from_env_for_terminal`](Self::from_env_for_terminal) instead.
+    pub fn from_env(var: &str, format: SolinkJsonFormat) -> Self {
+        Self::from_env_for_terminal(var, format, std::io::stdout().is_terminal())
+    }
+
+    /// Like [`from_env`](Self::from_env), where `terminal` is whether the
+    /// writer the format is used with writes to a terminal, such as
+    /// `std::io::stderr().is_terminal()`.
+    pub fn from_env_for_terminal(var: &str, format: SolinkJsonFormat, terminal: bool) -> Self {
+        let ansi = terminal && std::env::var_os("NO_COLOR").is_none();
+        match std::env::var(var).as_deref() {
+            Ok("json") => SolinkFormat::Json(format),
+            Ok("logfmt") => SolinkFormat::Logfmt(format.into()),
+            Ok("pretty") => SolinkFormat::Pretty(SolinkPrettyFormat::from(format).with_ansi(ansi)),
+            _ if terminal => SolinkFormat::Pretty(SolinkPrettyFormat::from(format).with_ansi(ansi)),
+            _ => SolinkFormat::Json(format),
+        }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for SolinkFormat
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        match self {
+            SolinkFormat::Json(format) => format.format_event(ctx, writer, event),
+            SolinkFormat::Logfmt(format) => format.format_event(ctx, writer, event),
+            SolinkFormat::Pretty(format) => format.format_event(ctx, writer, event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{dispatcher, warn};
+    use tracing_subscriber::{fmt::format::JsonFields, layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{test_support::TestWriter, Clock};
+
+    fn capture(format: impl FormatEvent<Registry, JsonFields> + Send + Sync + 'static) -> String {
+        let writer = TestWriter::new();
+        let layer = {
+            let writer = writer.clone();
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(JsonFields::new())
+                .event_format(format)
+                .with_writer(move || writer.clone())
+        };
+
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, || {
+            let span = tracing::info_span!("handle", user = "bob smith");
+            let _s = span.enter();
+            warn!(order_id = 42, "Order placed")
+        });
+
+        writer.contents()
+    }
+
+    fn format() -> SolinkJsonFormat {
+        SolinkJsonFormat::new()
+            .with_timestamp_format(crate::TimestampFormat::Rfc3339(crate::SecondsFormat::Secs))
+            .with_clock(Clock::fixed("2024-06-18T21:17:44Z".parse().unwrap()))
+    }
+
+    #[test]
+    fn should_write_header_then_fields() {
+        assert_eq!(
+            capture(SolinkPrettyFormat::from(format()).with_ansi(false)),
+            concat!(
+                "2024-06-18T21:17:44Z  WARN solink_tracing_flat_json::pretty::tests: Order placed ",
+                "order_id=42 span=handle user=\"bob smith\"\n",
+            ),
+        );
+        assert_eq!(
+            capture(SolinkPrettyFormat::from(format().with_target(false))),
+            concat!(
+                "\x1b[2m2024-06-18T21:17:44Z\x1b[0m \x1b[33m WARN\x1b[0m Order placed ",
+                "\x1b[3;36morder_id\x1b[0m\x1b[2m=\x1b[0m42 \x1b[3;36mspan\x1b[0m\x1b[2m=\x1b[0mhandle ",
+                "\x1b[3;36muser\x1b[0m\x1b[2m=\x1b[0m\"bob smith\"\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn should_choose_the_format_from_the_environment() {
+        std::env::set_var("SOLINK_TEST_LOG_FORMAT", "logfmt");
+        let line = capture(SolinkFormat::from_env("SOLINK_TEST_LOG_FORMAT", format()));
+        assert!(
+            line.starts_with("timestamp=2024-06-18T21:17:44Z level=WARN"),
+            "{}",
+            line
+        );
+
+        std::env::set_var("SOLINK_TEST_LOG_FORMAT", "json");
+        let line = capture(SolinkFormat::from_env("SOLINK_TEST_LOG_FORMAT", format()));
+        assert!(
+            line.starts_with(r#"{"timestamp":"2024-06-18T21:17:44Z""#),
+            "{}",
+            line
+        );
+
+        std::env::remove_var("SOLINK_TEST_LOG_FORMAT");
+        let format = |terminal| {
+            SolinkFormat::from_env_for_terminal("SOLINK_TEST_LOG_FORMAT", format(), terminal)
+        };
+        assert!(matches!(format(true), SolinkFormat::Pretty(_)));
+        assert!(matches!(format(false), SolinkFormat::Json(_)));
+    }
+}
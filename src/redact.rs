@@ -0,0 +1,341 @@
+//! Redaction of sensitive event and span fields.
+
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::fields::FieldValue;
+
+/// Redacts sensitive event and span fields before they are written.
+///
+/// Fields are redacted by name, or by scanning string values for patterns
+/// such as email addresses. Because span fields are repeated on every event
+/// in the span, redacting them here covers every line they would appear on.
+///
+/// ```rs
+///     let redactor = Redactor::new()
+///         .redact_key("password")
+///         .redact_key_ignore_case("authorization")
+///         .redact_key_glob("*_token")
+///         .scan_values(ValueScanner::email())
+///         .with_replacement(Replacement::Mask("***".into()));
+///     let format = SolinkJsonFormat::new().with_redactor(redactor);
+/// ```
+///
+/// Built-in and static fields are never redacted.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    keys: Vec<KeyPattern>,
+    scanners: Vec<ValueScanner>,
+    replacement: Replacement,
+}
+
+/// What to replace redacted data with.
+#[derive(Debug, Clone)]
+pub enum Replacement {
+    /// Replace the data with a fixed string. The default is `[REDACTED]`.
+    Mask(String),
+    /// Leave out the whole field.
+    Drop,
+    /// Replace the data with a hex-encoded HMAC-SHA256 of it, keyed with the
+    /// given salt, so that equal values can still be correlated.
+    Hash { salt: Vec<u8> },
+}
+
+impl Default for Replacement {
+    fn default() -> Self {
+        Replacement::Mask("[REDACTED]".to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum KeyPattern {
+    Exact(String),
+    IgnoreCase(String),
+    /// Always ignores ASCII case.
+    Glob(Vec<char>),
+}
+
+impl KeyPattern {
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyPattern::Exact(name) => name == key,
+            KeyPattern::IgnoreCase(name) => name.eq_ignore_ascii_case(key),
+            KeyPattern::Glob(pattern) => glob_matches(pattern, &key.chars().collect::<Vec<_>>()),
+        }
+    }
+}
+
+/// Match `text` against a pattern where `*` matches any run of characters and
+/// `?` matches any one character, ignoring ASCII case.
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*`, if the text stops matching.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Finds sensitive data inside string values.
+#[derive(Debug, Clone)]
+pub struct ValueScanner {
+    regex: Regex,
+    /// Further check on each match, to rule out false positives.
+    validate: Option<fn(&str) -> bool>,
+}
+
+impl ValueScanner {
+    /// Scan for matches of a regular expression.
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            regex: Regex::new(pattern)?,
+            validate: None,
+        })
+    }
+
+    /// Scan for email addresses.
+    pub fn email() -> Self {
+        Self::builtin(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}")
+    }
+
+    /// Scan for payment card numbers: 13 to 19 digits, optionally grouped
+    /// with spaces or dashes, that pass the Luhn check.
+    pub fn card_number() -> Self {
+        Self {
+            validate: Some(passes_luhn_check),
+            ..Self::builtin(r"\b\d(?:[ -]?\d){12,18}\b")
+        }
+    }
+
+    /// Scan for bearer tokens, as found in `Authorization` headers.
+    pub fn bearer_token() -> Self {
+        Self::builtin(r"(?i)\bbearer\s+[A-Za-z0-9\-._~+/]+=*")
+    }
+
+    fn builtin(pattern: &str) -> Self {
+        Self::new(pattern).expect("built-in patterns are valid")
+    }
+
+    fn is_match(&self, value: &str) -> bool {
+        self.regex.find_iter(value).any(|found| {
+            self.validate
+                .is_none_or(|validate| validate(found.as_str()))
+        })
+    }
+}
+
+fn passes_luhn_check(number: &str) -> bool {
+    let digits = number.chars().rev().filter_map(|c| c.to_digit(10));
+    let sum: u32 = digits
+        .enumerate()
+        .map(|(index, digit)| match index % 2 {
+            0 => digit,
+            _ if digit > 4 => digit * 2 - 9,
+            _ => digit * 2,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Redact fields with exactly this name.
+    pub fn redact_key(mut self, key: impl Into<String>) -> Self {
+        self.keys.push(KeyPattern::Exact(key.into()));
+        self
+    }
+
+    /// Redact fields with this name, ignoring ASCII case.
+    pub fn redact_key_ignore_case(mut self, key: impl Into<String>) -> Self {
+        self.keys.push(KeyPattern::IgnoreCase(key.into()));
+        self
+    }
+
+    /// Redact fields whose name matches a glob, where `*` matches any run of
+    /// characters and `?` any one character. The match ignores ASCII case.
+    pub fn redact_key_glob(mut self, pattern: &str) -> Self {
+        self.keys.push(KeyPattern::Glob(pattern.chars().collect()));
+        self
+    }
+
+    /// Redact whatever `scanner` finds in string values, leaving the rest of
+    /// the string as it is.
+    pub fn scan_values(mut self, scanner: ValueScanner) -> Self {
+        self.scanners.push(scanner);
+        self
+    }
+
+    /// Set what redacted data is replaced with. Defaults to `[REDACTED]`.
+    pub fn with_replacement(mut self, replacement: Replacement) -> Self {
+        self.replacement = replacement;
+        self
+    }
+
+    /// Redact a field, returning `None` if it should be left out.
+    pub(crate) fn redact(&self, key: &str, value: FieldValue) -> Option<FieldValue> {
+        if self.keys.iter().any(|pattern| pattern.matches(key)) {
+            return match &self.replacement {
+                Replacement::Drop => None,
+                _ => {
+                    let value = match value.as_value() {
+                        Value::String(value) => self.replace(value),
+                        value => self.replace(&value.to_string()),
+                    };
+                    Some(Value::String(value).into())
+                }
+            };
+        }
+
+        let Value::String(text) = value.as_value() else {
+            return Some(value);
+        };
+        if !self.scanners.iter().any(|scanner| scanner.is_match(text)) {
+            return Some(value);
+        }
+        if let Replacement::Drop = self.replacement {
+            return None;
+        }
+
+        let mut text = text.clone();
+        for scanner in &self.scanners {
+            text = scanner
+                .regex
+                .replace_all(&text, |captures: &regex::Captures<'_>| {
+                    let found = &captures[0];
+                    match scanner.validate {
+                        Some(validate) if !validate(found) => found.to_string(),
+                        _ => self.replace(found),
+                    }
+                })
+                .into_owned();
+        }
+        Some(Value::String(text).into())
+    }
+
+    fn replace(&self, data: &str) -> String {
+        match &self.replacement {
+            Replacement::Mask(mask) => mask.clone(),
+            Replacement::Drop => String::new(),
+            Replacement::Hash { salt } => {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(salt).expect("HMAC accepts keys of any length");
+                mac.update(data.as_bytes());
+                mac.finalize()
+                    .into_bytes()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn redact(redactor: &Redactor, key: &str, value: Value) -> Option<Value> {
+        redactor
+            .redact(key, value.into())
+            .map(|value| value.into_value())
+    }
+
+    #[test]
+    fn should_match_keys() {
+        let redactor = Redactor::new()
+            .redact_key("password")
+            .redact_key_ignore_case("authorization")
+            .redact_key_glob("*_TOKEN");
+
+        assert_eq!(
+            redact(&redactor, "password", json!(1234)),
+            Some(json!("[REDACTED]"))
+        );
+        assert_eq!(redact(&redactor, "Password", json!("x")), Some(json!("x")));
+        assert_eq!(
+            redact(&redactor, "AUTHORIZATION", json!("x")),
+            Some(json!("[REDACTED]"))
+        );
+        assert_eq!(
+            redact(&redactor, "api_token", json!("x")),
+            Some(json!("[REDACTED]"))
+        );
+        assert_eq!(redact(&redactor, "token_count", json!(2)), Some(json!(2)));
+    }
+
+    #[test]
+    fn should_replace_scanned_values() {
+        let redactor = Redactor::new()
+            .scan_values(ValueScanner::email())
+            .scan_values(ValueScanner::card_number())
+            .scan_values(ValueScanner::bearer_token());
+
+        assert_eq!(
+            redact(&redactor, "note", json!("mail bob@example.com or call")),
+            Some(json!("mail [REDACTED] or call")),
+        );
+        assert_eq!(
+            redact(
+                &redactor,
+                "note",
+                json!("paid with 4111 1111 1111 1111, order 1234567890123")
+            ),
+            Some(json!("paid with [REDACTED], order 1234567890123")),
+        );
+        assert_eq!(
+            redact(&redactor, "header", json!("Bearer abc.def-123")),
+            Some(json!("[REDACTED]")),
+        );
+
+        let redactor = redactor.with_replacement(Replacement::Drop);
+        assert_eq!(redact(&redactor, "note", json!("bob@example.com")), None);
+        assert_eq!(
+            redact(&redactor, "note", json!("nothing here")),
+            Some(json!("nothing here"))
+        );
+    }
+
+    #[test]
+    fn should_hash_with_the_salt() {
+        let redactor = |salt: &[u8]| {
+            Redactor::new()
+                .redact_key("user")
+                .with_replacement(Replacement::Hash {
+                    salt: salt.to_vec(),
+                })
+        };
+
+        let hashed = redact(&redactor(b"a"), "user", json!("bob")).unwrap();
+        assert_eq!(hashed.as_str().unwrap().len(), 64);
+        assert_eq!(
+            Some(hashed.clone()),
+            redact(&redactor(b"a"), "user", json!("bob"))
+        );
+        assert_ne!(Some(hashed), redact(&redactor(b"b"), "user", json!("bob")));
+    }
+}
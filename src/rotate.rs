@@ -0,0 +1,709 @@
+//! A `MakeWriter` for log files that rotate by size or time, with
+//! compression and retention.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Sender},
+        Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use chrono::{DateTime, DurationRound, NaiveDateTime, TimeDelta, Utc};
+use flate2::{write::GzEncoder, Compression};
+use tracing_appender::rolling::Rotation;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::Clock;
+
+/// The format of the times rotated files are named after.
+const TIME_FORMAT: &str = "%Y-%m-%dT%H-%M-%S-%3f";
+
+/// Options for a [`RotatingFileWriter`]:
+///
+/// ```rs
+///     let writer = RotatingFile::new("/var/log/app", "app")
+///         .with_max_bytes(100 * 1024 * 1024)
+///         .with_rotation(Rotation::DAILY)
+///         .with_compression(true)
+///         .with_max_files(10)
+///         .open()?;
+/// ```
+///
+/// Events are written to `app.log`. When a new period starts, it is renamed
+/// after the start of the period it holds, such as
+/// `app.2024-06-18T21-00-00-000.log`, and a new `app.log` is started. When
+/// it would grow past the maximum size, it is renamed the same way with a
+/// sequence number, such as `app.2024-06-18T21-00-00-000.1.log`, or after the
+/// time when rotating by size alone.
+#[derive(Debug, Clone)]
+pub struct RotatingFile {
+    dir: PathBuf,
+    prefix: String,
+    max_bytes: Option<u64>,
+    rotation: Rotation,
+    compress: bool,
+    retention: Retention,
+    clock: Clock,
+}
+
+/// Which rotated files to keep.
+#[derive(Debug, Clone, Default)]
+struct Retention {
+    max_files: Option<usize>,
+    max_total_bytes: Option<u64>,
+}
+
+impl RotatingFile {
+    /// Write to `{prefix}.log` in `dir`, never rotating it until another
+    /// option says when to.
+    pub fn new(dir: impl AsRef<Path>, prefix: impl Into<String>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            prefix: prefix.into(),
+            max_bytes: None,
+            rotation: Rotation::NEVER,
+            compress: false,
+            retention: Retention::default(),
+            clock: Clock::default(),
+        }
+    }
+
+    /// Rotate the file before it grows past `max_bytes`. A single event
+    /// larger than this still gets a file of its own.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Rotate the file when a new minute, hour or day starts, in UTC. Other
+    /// rotations make [`open`](Self::open) fail.
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Set whether to gzip rotated files, on a background thread. Off by
+    /// default.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Keep at most `max_files` rotated files, deleting the oldest.
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.retention.max_files = Some(max_files);
+        self
+    }
+
+    /// Delete the oldest rotated files until the size of every file,
+    /// including the one being written, is at most `max_total_bytes`.
+    pub fn with_max_total_bytes(mut self, max_total_bytes: u64) -> Self {
+        self.retention.max_total_bytes = Some(max_total_bytes);
+        self
+    }
+
+    /// Set the clock used to decide when a new period starts, and to name
+    /// rotated files.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Create the directory if needed, and open the file for appending. If
+    /// the file already has events from an earlier period, as told by when
+    /// it was last modified, it is rotated before the first write.
+    ///
+    /// Fails with `InvalidInput` for a rotation other than `MINUTELY`,
+    /// `HOURLY`, `DAILY` or `NEVER`.
+    pub fn open(self) -> io::Result<RotatingFileWriter> {
+        if self.rotation != Rotation::NEVER && self.period_length().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported rotation {:?}", self.rotation),
+            ));
+        }
+        fs::create_dir_all(&self.dir)?;
+        let file = self.open_active()?;
+        let metadata = file.metadata()?;
+        let written = match metadata.len() {
+            0 => self.clock.now(),
+            _ => metadata.modified()?.into(),
+        };
+        let state = State {
+            size: metadata.len(),
+            file,
+            period: self.period(written),
+        };
+
+        let compressor = match self.compress {
+            true => Some(Compressor::spawn(self.files())?),
+            false => None,
+        };
+        Ok(RotatingFileWriter {
+            options: self,
+            state: Mutex::new(state),
+            compressor,
+        })
+    }
+
+    fn files(&self) -> Files {
+        Files {
+            dir: self.dir.clone(),
+            prefix: self.prefix.clone(),
+            retention: self.retention.clone(),
+        }
+    }
+
+    fn active_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.log", self.prefix))
+    }
+
+    fn open_active(&self) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.active_path())
+    }
+
+    /// The length of a period, or `None` for `NEVER` and rotations this
+    /// writer does not know.
+    fn period_length(&self) -> Option<TimeDelta> {
+        match &self.rotation {
+            rotation if *rotation == Rotation::MINUTELY => Some(TimeDelta::minutes(1)),
+            rotation if *rotation == Rotation::HOURLY => Some(TimeDelta::hours(1)),
+            rotation if *rotation == Rotation::DAILY => Some(TimeDelta::days(1)),
+            _ => None,
+        }
+    }
+
+    /// The start of the period `now` is in.
+    fn period(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        now.duration_trunc(self.period_length()?).ok()
+    }
+}
+
+/// `MakeWriter` that appends to a file, rotating it by size or time, and
+/// enforcing a retention policy on the rotated files. Created with
+/// [`RotatingFile::open`].
+///
+/// Every event is written to the file in one piece, so a line is never split
+/// across two files. The writer can also be wrapped with
+/// `tracing_appender::non_blocking`, as long as each write is a whole line.
+///
+/// Dropping the writer waits for rotated files to be compressed.
+pub struct RotatingFileWriter {
+    options: RotatingFile,
+    state: Mutex<State>,
+    compressor: Option<Compressor>,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    period: Option<DateTime<Utc>>,
+}
+
+impl RotatingFileWriter {
+    /// Append `data` to the file in one piece, rotating it first if needed.
+    fn write_whole(&self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+        let now = self.options.clock.now();
+        let period = self.options.period(now);
+
+        let too_big = self
+            .options
+            .max_bytes
+            .is_some_and(|max_bytes| state.size + data.len() as u64 > max_bytes);
+        if state.size > 0 {
+            match state.period {
+                Some(start) if period != state.period => self.rotate(&mut state, start, false)?,
+                start if too_big => self.rotate(&mut state, start.unwrap_or(now), true)?,
+                _ => {}
+            }
+        }
+        state.period = period;
+
+        state.file.write_all(data)?;
+        state.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Rename the file after `time`, with a sequence number if `numbered`.
+    fn rotate(&self, state: &mut State, time: DateTime<Utc>, numbered: bool) -> io::Result<()> {
+        state.file.flush()?;
+        let rotated = self.rotated_path(time, numbered);
+        fs::rename(self.options.active_path(), &rotated)?;
+        state.file = self.options.open_active()?;
+        state.size = 0;
+
+        match &self.compressor {
+            Some(compressor) => compressor.send(rotated),
+            None => self.options.files().retain(),
+        }
+        Ok(())
+    }
+
+    /// A name after `time` that is not taken yet. Sequence numbers start at 1
+    /// if `numbered`, so the file without one is the last of its period.
+    fn rotated_path(&self, time: DateTime<Utc>, numbered: bool) -> PathBuf {
+        let time = time.format(TIME_FORMAT);
+        let (dir, prefix) = (&self.options.dir, &self.options.prefix);
+        (u32::from(numbered)..)
+            .map(|n| match n {
+                0 => dir.join(format!("{}.{}.log", prefix, time)),
+                n => dir.join(format!("{}.{}.{}.log", prefix, time, n)),
+            })
+            .find(|path| !path.exists() && !gz_path(path).exists())
+            .expect("some sequence number is free")
+    }
+}
+
+impl io::Write for RotatingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_whole(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+        state.file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFileWriter {
+    type Writer = EventWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventWriter {
+            writer: self,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Buffers everything written for one event, so it goes in a single file.
+pub struct EventWriter<'a> {
+    writer: &'a RotatingFileWriter,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for EventWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// Write the complete lines buffered so far.
+    fn flush(&mut self) -> io::Result<()> {
+        let end = match self.buffer.iter().rposition(|byte| *byte == b'\n') {
+            Some(newline) => newline + 1,
+            None => return Ok(()),
+        };
+        self.writer.write_whole(&self.buffer[..end])?;
+        self.buffer.drain(..end);
+        Ok(())
+    }
+}
+
+impl Drop for EventWriter<'_> {
+    fn drop(&mut self) {
+        if let Err(error) = self.writer.write_whole(&self.buffer) {
+            eprintln!("failed to write log file: {}", error);
+        }
+    }
+}
+
+/// The rotated files in a directory, and which of them to keep.
+struct Files {
+    dir: PathBuf,
+    prefix: String,
+    retention: Retention,
+}
+
+impl Files {
+    /// Delete the oldest rotated files the retention policy does not keep.
+    fn retain(&self) {
+        if let Err(error) = self.try_retain() {
+            eprintln!("failed to delete old log files: {}", error);
+        }
+    }
+
+    fn try_retain(&self) -> io::Result<()> {
+        let Retention {
+            max_files,
+            max_total_bytes,
+        } = self.retention;
+        if max_files.is_none() && max_total_bytes.is_none() {
+            return Ok(());
+        }
+
+        let active = self.dir.join(format!("{}.log", self.prefix));
+        let mut total = fs::metadata(active).map_or(0, |metadata| metadata.len());
+        let mut files = self.rotated()?;
+        files.sort_by(|a, b| b.order.cmp(&a.order));
+        for (index, file) in files.into_iter().enumerate() {
+            total += file.size;
+            let too_many = max_files.is_some_and(|max_files| index >= max_files);
+            let too_big = max_total_bytes.is_some_and(|max_total_bytes| total > max_total_bytes);
+            if too_many || too_big {
+                remove(&file.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every rotated file, in any order.
+    fn rotated(&self) -> io::Result<Vec<RotatedFile>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else { continue };
+            let rest = name.strip_prefix(&self.prefix).and_then(|rest| {
+                let rest = rest.strip_prefix('.')?;
+                rest.strip_suffix(".log")
+                    .or_else(|| rest.strip_suffix(".log.gz"))
+            });
+            let Some(rest) = rest else { continue };
+            let (time, n) = match rest.split_once('.') {
+                Some((time, n)) => match n.parse() {
+                    Ok(n) => (time, n),
+                    Err(_) => continue,
+                },
+                // Rotated when its period ended, after any numbered ones.
+                None => (rest, u32::MAX),
+            };
+            // Such as the active file of the prefix `app.worker`.
+            if NaiveDateTime::parse_from_str(time, TIME_FORMAT).is_err() {
+                continue;
+            }
+            let order = (time.to_string(), n);
+            files.push(RotatedFile {
+                order,
+                path: entry.path(),
+                size: entry.metadata()?.len(),
+            });
+        }
+        Ok(files)
+    }
+}
+
+struct RotatedFile {
+    /// The time the file is named after, and its sequence number for that
+    /// time.
+    order: (String, u32),
+    path: PathBuf,
+    size: u64,
+}
+
+/// Gzips rotated files on a background thread, then enforces retention.
+struct Compressor {
+    sender: Option<Sender<PathBuf>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compressor {
+    fn spawn(files: Files) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<PathBuf>();
+        let thread = std::thread::Builder::new()
+            .name("log-compressor".to_string())
+            .spawn(move || {
+                for path in receiver {
+                    if let Err(error) = gzip(&path) {
+                        eprintln!("failed to compress {}: {}", path.display(), error);
+                    }
+                    files.retain();
+                }
+            })?;
+        Ok(Self {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    fn send(&self, path: PathBuf) {
+        if let Some(sender) = &self.sender {
+            // The thread only stops once the sender is dropped.
+            let _ = sender.send(path);
+        }
+    }
+}
+
+impl Drop for Compressor {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Replace the file at `path` with a gzipped copy.
+fn gzip(path: &Path) -> io::Result<()> {
+    let mut input = match File::open(path) {
+        Ok(input) => input,
+        // Already deleted by the retention policy.
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    let gz_path = gz_path(path);
+    let mut output = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+    io::copy(&mut input, &mut output)?;
+    output.finish()?.sync_all()?;
+    remove(path)
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+    gz_path.into()
+}
+
+/// Delete a file, unless it is already gone.
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        sync::{
+            atomic::{AtomicI64, Ordering},
+            Arc,
+        },
+    };
+
+    use flate2::read::GzDecoder;
+    use serde_json::Value;
+    use tracing::{dispatcher, info};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{FlatJsonLayer, SolinkJsonFormat};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("solink-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn log(writer: RotatingFileWriter, events: usize) {
+        let layer = FlatJsonLayer::new()
+            .with_format(SolinkJsonFormat::new().with_timestamp(false))
+            .with_writer(writer);
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, || {
+            for n in 0..events {
+                info!(n, "Event")
+            }
+        });
+    }
+
+    /// The names of the files in `dir`, and their contents, decompressed.
+    fn files(dir: &Path) -> Vec<(String, String)> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let mut contents = String::new();
+                let mut file = File::open(&path).unwrap();
+                match path.extension().unwrap().to_str() {
+                    Some("gz") => GzDecoder::new(file).read_to_string(&mut contents),
+                    _ => file.read_to_string(&mut contents),
+                }
+                .unwrap();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, contents)
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn should_rotate_by_size_compress_and_keep_the_newest_files() {
+        let dir = temp_dir("rotate-size");
+        let writer = RotatingFile::new(&dir, "app")
+            .with_max_bytes(200)
+            .with_compression(true)
+            .with_max_files(2)
+            .with_clock(Clock::fixed("2024-06-18T21:17:44.902Z".parse().unwrap()))
+            .open()
+            .unwrap();
+        log(writer, 10);
+
+        let files = files(&dir);
+        let names: Vec<_> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "app.2024-06-18T21-17-44-902.3.log.gz",
+                "app.2024-06-18T21-17-44-902.4.log.gz",
+                "app.log",
+            ],
+        );
+
+        // Each file only has whole lines, and the newest events are kept.
+        let events: Vec<u64> = files
+            .iter()
+            .flat_map(|(_, contents)| contents.lines())
+            .map(|line| {
+                serde_json::from_str::<Value>(line).unwrap()["n"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(events, [4, 5, 6, 7, 8, 9]);
+        for (_, contents) in &files {
+            assert!(contents.len() <= 200 && contents.ends_with('\n'));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_rotate_when_a_new_period_starts() {
+        let dir = temp_dir("rotate-time");
+        let minutes = Arc::new(AtomicI64::new(0));
+        let clock = {
+            let minutes = minutes.clone();
+            let start: DateTime<Utc> = "2024-06-18T21:59:30Z".parse().unwrap();
+            Clock::from_fn(move || start + TimeDelta::minutes(minutes.load(Ordering::SeqCst)))
+        };
+        let writer = RotatingFile::new(&dir, "app")
+            .with_rotation(Rotation::HOURLY)
+            .with_max_total_bytes(15)
+            .with_clock(clock)
+            .open()
+            .unwrap();
+
+        let write = |minute, line: &str| {
+            minutes.store(minute, Ordering::SeqCst);
+            writeln!(writer.make_writer(), "{}", line).unwrap();
+        };
+        let file = |name: &str, contents: &str| (name.to_string(), contents.to_string());
+        write(0, "first");
+        write(0, "second");
+        write(1, "third");
+        assert_eq!(
+            files(&dir),
+            [
+                file("app.2024-06-18T21-00-00-000.log", "first\nsecond\n"),
+                file("app.log", "third\n"),
+            ],
+        );
+
+        // The oldest file no longer fits in the total size.
+        write(70, "fourth");
+        assert_eq!(
+            files(&dir),
+            [
+                file("app.2024-06-18T22-00-00-000.log", "third\n"),
+                file("app.log", "fourth\n"),
+            ],
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_number_size_rotations_within_a_period() {
+        let dir = temp_dir("rotate-both");
+        let minutes = Arc::new(AtomicI64::new(0));
+        let clock = {
+            let minutes = minutes.clone();
+            let start: DateTime<Utc> = "2024-06-18T21:59:30Z".parse().unwrap();
+            Clock::from_fn(move || start + TimeDelta::minutes(minutes.load(Ordering::SeqCst)))
+        };
+        let writer = RotatingFile::new(&dir, "app")
+            .with_rotation(Rotation::HOURLY)
+            .with_max_bytes(7)
+            .with_max_files(2)
+            .with_clock(clock)
+            .open()
+            .unwrap();
+
+        let write = |minute, line: &str| {
+            minutes.store(minute, Ordering::SeqCst);
+            writeln!(writer.make_writer(), "{}", line).unwrap();
+        };
+        let file = |name: &str, contents: &str| (name.to_string(), contents.to_string());
+        write(0, "first");
+        write(0, "second");
+        write(0, "third");
+        write(1, "fourth");
+        // The file closed by the new period is the newest rotated file.
+        assert_eq!(
+            files(&dir),
+            [
+                file("app.2024-06-18T21-00-00-000.2.log", "second\n"),
+                file("app.2024-06-18T21-00-00-000.log", "third\n"),
+                file("app.log", "fourth\n"),
+            ],
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_only_delete_files_named_after_a_time() {
+        let dir = temp_dir("rotate-prefix");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.worker.log"), "other\n").unwrap();
+        let writer = RotatingFile::new(&dir, "app")
+            .with_max_bytes(7)
+            .with_max_files(0)
+            .with_clock(Clock::fixed("2024-06-18T21:17:44.902Z".parse().unwrap()))
+            .open()
+            .unwrap();
+        writeln!(writer.make_writer(), "first").unwrap();
+        writeln!(writer.make_writer(), "second").unwrap();
+
+        let file = |name: &str, contents: &str| (name.to_string(), contents.to_string());
+        assert_eq!(
+            files(&dir),
+            [
+                file("app.log", "second\n"),
+                file("app.worker.log", "other\n")
+            ],
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_rotate_a_file_left_from_an_earlier_period() {
+        let dir = temp_dir("rotate-reopen");
+        fs::create_dir_all(&dir).unwrap();
+        let yesterday: DateTime<Utc> = "2024-06-17T23:59:00Z".parse().unwrap();
+        File::create(dir.join("app.log"))
+            .and_then(|mut file| {
+                file.write_all(b"old\n")?;
+                file.set_modified(yesterday.into())
+            })
+            .unwrap();
+        let writer = RotatingFile::new(&dir, "app")
+            .with_rotation(Rotation::DAILY)
+            .with_clock(Clock::fixed("2024-06-18T08:00:00Z".parse().unwrap()))
+            .open()
+            .unwrap();
+        writeln!(writer.make_writer(), "new").unwrap();
+
+        let file = |name: &str, contents: &str| (name.to_string(), contents.to_string());
+        assert_eq!(
+            files(&dir),
+            [
+                file("app.2024-06-17T00-00-00-000.log", "old\n"),
+                file("app.log", "new\n"),
+            ],
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
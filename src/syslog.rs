@@ -0,0 +1,404 @@
+//! A `MakeWriter` that sends each event to syslog as an RFC 5424 message.
+
+use std::{
+    io,
+    net::{ToSocketAddrs, UdpSocket},
+    path::Path,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{
+    sink::{text, udp_socket, LineFields},
+    Clock,
+};
+
+/// The syslog facility messages are sent with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Facility {
+    Kern = 0,
+    /// The default.
+    #[default]
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    AuthPriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// The syslog severity for a level.
+pub(crate) fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// `MakeWriter` that sends each event to a syslog daemon as an RFC 5424
+/// message, one datagram per event:
+///
+/// ```txt
+/// <14>1 2024-06-18T21:17:44.902137Z host app 4242 - - {"level":"INFO","message":"Hello"}
+/// ```
+///
+/// The priority combines the facility with a severity mapped from the
+/// event's level. The line written by the formatter is the `MSG`, or, with
+/// [`with_structured_data`](Self::with_structured_data), its fields are sent
+/// as SD-PARAMs and the message alone is the `MSG`.
+pub struct SyslogWriter {
+    socket: Socket,
+    facility: Facility,
+    hostname: String,
+    app_name: String,
+    structured_data: Option<String>,
+    message_key: String,
+    clock: Clock,
+}
+
+enum Socket {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+impl SyslogWriter {
+    /// Send to the local syslog daemon at `/dev/log`.
+    #[cfg(unix)]
+    pub fn local() -> io::Result<Self> {
+        Self::unix("/dev/log")
+    }
+
+    /// Send to the Unix datagram socket at `path`.
+    #[cfg(unix)]
+    pub fn unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self::new(Socket::Unix(socket)))
+    }
+
+    /// Send over UDP to `addr`, such as `"syslog.internal:514"`.
+    pub fn udp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self::new(Socket::Udp(udp_socket(addr)?)))
+    }
+
+    fn new(socket: Socket) -> Self {
+        Self {
+            socket,
+            facility: Facility::default(),
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+            app_name: crate::init::file_prefix(),
+            structured_data: None,
+            message_key: "message".to_string(),
+            clock: Clock::default(),
+        }
+    }
+
+    /// Set the facility. `User` by default.
+    pub fn with_facility(mut self, facility: Facility) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    /// Set the `HOSTNAME`. The machine's host name by default.
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = hostname.into();
+        self
+    }
+
+    /// Set the `APP-NAME`. The name of the executable by default.
+    pub fn with_app_name(mut self, app_name: impl Into<String>) -> Self {
+        self.app_name = app_name.into();
+        self
+    }
+
+    /// Send the fields of each JSON line as SD-PARAMs of an SD-ELEMENT with
+    /// the given SD-ID, such as `fields@32473`, and only the message as the
+    /// `MSG`. Lines that are not JSON objects are still sent as the `MSG`.
+    pub fn with_structured_data(mut self, sd_id: impl Into<String>) -> Self {
+        self.structured_data = Some(sd_id.into());
+        self
+    }
+
+    /// Set the key of the message field, if it was renamed, for
+    /// [`with_structured_data`](Self::with_structured_data).
+    pub fn with_message_key(mut self, message_key: impl Into<String>) -> Self {
+        self.message_key = message_key.into();
+        self
+    }
+
+    /// Set the clock used for the `TIMESTAMP`.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Frame `line`, written for an event at `level`, as a syslog message.
+    fn message(&self, level: &Level, line: &[u8]) -> Vec<u8> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let priority = self.facility as u8 * 8 + severity(level);
+        let timestamp = self
+            .clock
+            .now()
+            .to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+        let mut message = format!(
+            "<{}>1 {} {} {} {} - ",
+            priority,
+            timestamp,
+            header_field(&self.hostname, 255),
+            header_field(&self.app_name, 48),
+            std::process::id(),
+        )
+        .into_bytes();
+
+        let fields = self
+            .structured_data
+            .as_ref()
+            .and_then(|sd_id| Some((sd_id, LineFields::parse(line)?)));
+        match fields {
+            Some((sd_id, mut fields)) => {
+                let msg = fields.take(&self.message_key);
+                message.push(b'[');
+                message.extend_from_slice(sd_name(sd_id).as_bytes());
+                for (key, value) in &fields.0 {
+                    let param = format!(" {}=\"{}\"", sd_name(key), param_value(&text(value)));
+                    message.extend_from_slice(param.as_bytes());
+                }
+                message.push(b']');
+                if let Some(msg) = msg {
+                    message.push(b' ');
+                    message.extend_from_slice(text(&msg).as_bytes());
+                }
+            }
+            None => {
+                message.extend_from_slice(b"- ");
+                message.extend_from_slice(line);
+            }
+        }
+        message
+    }
+
+    fn send(&self, level: &Level, line: &[u8]) -> io::Result<()> {
+        let message = self.message(level, line);
+        match &self.socket {
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(&message)?,
+            Socket::Udp(socket) => socket.send(&message)?,
+        };
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogMessage<'a>;
+
+    /// Events written without their metadata are sent at `INFO`.
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogMessage {
+            writer: self,
+            level: Level::INFO,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogMessage {
+            writer: self,
+            level: *meta.level(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Buffers the line written for one event, and sends it when dropped.
+pub struct SyslogMessage<'a> {
+    writer: &'a SyslogWriter,
+    level: Level,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for SyslogMessage<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogMessage<'_> {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        if let Err(error) = self.writer.send(&self.level, &self.buffer) {
+            eprintln!("failed to send log to syslog: {}", error);
+        }
+    }
+}
+
+/// A header field: printable ASCII without spaces, at most `max_len` long,
+/// or `-` if empty.
+fn header_field(value: &str, max_len: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    match value.is_empty() {
+        true => "-".to_string(),
+        false => value,
+    }
+}
+
+/// An SD-ID or PARAM-NAME: at most 32 printable ASCII characters, other
+/// than `=`, ` `, `]` and `"`, which are replaced by `_`.
+fn sd_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '=' | ']' | '"' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .take(32)
+        .collect()
+}
+
+/// A PARAM-VALUE, with `"`, `\` and `]` escaped.
+fn param_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{dispatcher, info, warn};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{FlatJsonLayer, SolinkJsonFormat};
+
+    fn log(writer: SyslogWriter) {
+        let writer = writer
+            .with_hostname("host")
+            .with_app_name("app")
+            .with_clock(Clock::fixed("2024-06-18T21:17:44.902137Z".parse().unwrap()));
+        let layer = FlatJsonLayer::new()
+            .with_format(
+                SolinkJsonFormat::new()
+                    .with_timestamp(false)
+                    .with_target(false),
+            )
+            .with_writer(writer);
+        let dispatch = dispatcher::Dispatch::new(Registry::default().with(layer));
+        dispatcher::with_default(&dispatch, || {
+            info!(user = "bob \"the\" builder", "Hello");
+            warn!(x = 7, "Careful]");
+        });
+    }
+
+    fn receive(recv: impl Fn(&mut [u8]) -> io::Result<usize>) -> Vec<String> {
+        let mut buffer = [0; 1024];
+        (0..2)
+            .map(|_| {
+                let len = recv(&mut buffer).unwrap();
+                String::from_utf8(buffer[..len].to_vec()).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_send_json_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        log(SyslogWriter::udp(server.local_addr().unwrap())
+            .unwrap()
+            .with_facility(Facility::Local3));
+
+        let pid = std::process::id();
+        assert_eq!(
+            receive(|buffer| server.recv(buffer)),
+            [
+                format!(
+                    "<158>1 2024-06-18T21:17:44.902137Z host app {} - - {}",
+                    pid, r#"{"level":"INFO","message":"Hello","user":"bob \"the\" builder"}"#
+                ),
+                format!(
+                    "<156>1 2024-06-18T21:17:44.902137Z host app {} - - {}",
+                    pid, r#"{"level":"WARN","message":"Careful]","x":7}"#
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn should_send_over_udp_to_an_ipv6_address() {
+        let Ok(server) = UdpSocket::bind("[::1]:0") else {
+            // No IPv6 loopback in this environment.
+            return;
+        };
+        log(SyslogWriter::udp(server.local_addr().unwrap()).unwrap());
+
+        let pid = std::process::id();
+        assert_eq!(
+            receive(|buffer| server.recv(buffer))[0],
+            format!(
+                "<14>1 2024-06-18T21:17:44.902137Z host app {} - - {}",
+                pid, r#"{"level":"INFO","message":"Hello","user":"bob \"the\" builder"}"#
+            ),
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_send_structured_data_over_a_unix_socket() {
+        let path = std::env::temp_dir().join(format!("solink-syslog-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+        log(SyslogWriter::unix(&path)
+            .unwrap()
+            .with_structured_data("fields@32473"));
+
+        let pid = std::process::id();
+        assert_eq!(
+            receive(|buffer| server.recv(buffer)),
+            [
+                format!(
+                    "<14>1 2024-06-18T21:17:44.902137Z host app {} - {} Hello",
+                    pid, r#"[fields@32473 level="INFO" user="bob \"the\" builder"]"#
+                ),
+                format!(
+                    "<12>1 2024-06-18T21:17:44.902137Z host app {} - {} Careful]",
+                    pid, r#"[fields@32473 level="WARN" x="7"]"#
+                ),
+            ],
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
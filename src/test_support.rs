@@ -0,0 +1,45 @@
+//! Helpers shared by the tests of every module.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A writer that keeps everything written to it, shared between clones.
+#[derive(Debug, Clone)]
+pub(crate) struct TestWriter {
+    pub(crate) data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl TestWriter {
+    pub(crate) fn new() -> Self {
+        Self {
+            data: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Everything written so far.
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8(self.data.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl io::Write for TestWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for TestWriter {
+    type Writer = TestWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
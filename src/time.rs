@@ -0,0 +1,174 @@
+//! Timestamp formats, and the clock timestamps are read from.
+
+use std::{fmt, sync::Arc};
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use serde_json::Value;
+use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
+
+/// How to write the timestamp of each event.
+#[non_exhaustive]
+pub enum TimestampFormat {
+    /// An RFC 3339 string in UTC, such as `2024-06-18T21:17:44.902137000Z`,
+    /// with the given precision. This is the default, with nanoseconds.
+    Rfc3339(SecondsFormat),
+    /// An RFC 3339 string in the local time zone, such as
+    /// `2024-06-18T17:17:44.902+04:00`, with the given precision.
+    Rfc3339Local(SecondsFormat),
+    /// Whole seconds since the Unix epoch, as a number.
+    UnixSeconds,
+    /// Milliseconds since the Unix epoch, as a number.
+    UnixMillis,
+    /// Microseconds since the Unix epoch, as a number.
+    UnixMicros,
+    /// Nanoseconds since the Unix epoch, as a number.
+    UnixNanos,
+    /// A string written by any `FormatTime`. These read the time themselves,
+    /// so the formatter's [`Clock`] is not used.
+    Custom(Box<dyn FormatTime + Send + Sync>),
+}
+
+impl TimestampFormat {
+    pub(crate) fn format(&self, clock: &Clock) -> Value {
+        match self {
+            TimestampFormat::Rfc3339(precision) => {
+                clock.now().to_rfc3339_opts(*precision, true).into()
+            }
+            TimestampFormat::Rfc3339Local(precision) => clock
+                .now()
+                .with_timezone(&Local)
+                .to_rfc3339_opts(*precision, true)
+                .into(),
+            TimestampFormat::UnixSeconds => clock.now().timestamp().into(),
+            TimestampFormat::UnixMillis => clock.now().timestamp_millis().into(),
+            TimestampFormat::UnixMicros => clock.now().timestamp_micros().into(),
+            // Out of range after the year 2262.
+            TimestampFormat::UnixNanos => clock.now().timestamp_nanos_opt().into(),
+            TimestampFormat::Custom(timer) => {
+                let mut timestamp = String::new();
+                match timer.format_time(&mut Writer::new(&mut timestamp)) {
+                    Ok(()) => timestamp.into(),
+                    Err(_) => Value::Null,
+                }
+            }
+        }
+    }
+}
+
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        TimestampFormat::Rfc3339(SecondsFormat::Nanos)
+    }
+}
+
+impl fmt::Debug for TimestampFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestampFormat::Rfc3339(precision) => {
+                f.debug_tuple("Rfc3339").field(precision).finish()
+            }
+            TimestampFormat::Rfc3339Local(precision) => {
+                f.debug_tuple("Rfc3339Local").field(precision).finish()
+            }
+            TimestampFormat::UnixSeconds => f.write_str("UnixSeconds"),
+            TimestampFormat::UnixMillis => f.write_str("UnixMillis"),
+            TimestampFormat::UnixMicros => f.write_str("UnixMicros"),
+            TimestampFormat::UnixNanos => f.write_str("UnixNanos"),
+            TimestampFormat::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Where timestamps are read from.
+///
+/// The system clock by default. Tests can use a fixed clock to get the same
+/// output on every run:
+///
+/// ```rs
+///     let format = SolinkJsonFormat::new().with_clock(Clock::fixed(
+///         "2024-06-18T21:17:44Z".parse().unwrap(),
+///     ));
+/// ```
+#[derive(Clone, Default)]
+pub struct Clock {
+    now: Option<Arc<dyn Fn() -> DateTime<Utc> + Send + Sync>>,
+}
+
+impl Clock {
+    /// The system clock.
+    pub fn system() -> Self {
+        Self::default()
+    }
+
+    /// A clock that is always at `time`.
+    pub fn fixed(time: DateTime<Utc>) -> Self {
+        Self::from_fn(move || time)
+    }
+
+    /// A clock that reads the time from `now`.
+    pub fn from_fn(now: impl Fn() -> DateTime<Utc> + Send + Sync + 'static) -> Self {
+        Self {
+            now: Some(Arc::new(now)),
+        }
+    }
+
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        match &self.now {
+            Some(now) => now(),
+            None => Utc::now(),
+        }
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.now {
+            Some(_) => f.write_str("Clock::from_fn(..)"),
+            None => f.write_str("Clock::system()"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_format_each_timestamp_format() {
+        let clock = Clock::fixed("2024-06-18T21:17:44.902137Z".parse().unwrap());
+
+        let cases = [
+            (
+                TimestampFormat::default(),
+                Value::from("2024-06-18T21:17:44.902137000Z"),
+            ),
+            (
+                TimestampFormat::Rfc3339(SecondsFormat::Millis),
+                Value::from("2024-06-18T21:17:44.902Z"),
+            ),
+            (TimestampFormat::UnixSeconds, Value::from(1718745464)),
+            (TimestampFormat::UnixMillis, Value::from(1718745464902_i64)),
+            (
+                TimestampFormat::UnixMicros,
+                Value::from(1718745464902137_i64),
+            ),
+            (
+                TimestampFormat::UnixNanos,
+                Value::from(1718745464902137000_i64),
+            ),
+        ];
+        for (format, expected) in cases {
+            assert_eq!(format.format(&clock), expected, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn should_format_local_time_with_an_offset() {
+        let time: DateTime<Utc> = "2024-06-18T21:17:44Z".parse().unwrap();
+        let format = TimestampFormat::Rfc3339Local(SecondsFormat::Secs);
+
+        let formatted = format.format(&Clock::fixed(time));
+        let parsed = DateTime::parse_from_rfc3339(formatted.as_str().unwrap()).unwrap();
+        assert_eq!(parsed, time);
+    }
+}
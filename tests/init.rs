@@ -0,0 +1,35 @@
+//! `init` installs the global subscriber, so it is tested in its own binary.
+
+use solink_tracing_flat_json::{init, InitError, Rotation, SolinkJsonFormat};
+use tracing::{info, warn};
+
+#[test]
+fn should_install_and_write_to_rolling_files() {
+    let dir = std::env::temp_dir().join(format!("solink-init-{}", std::process::id()));
+    std::env::set_var("SOLINK_TEST_INIT_FILTER", "warn");
+
+    let guards = init()
+        .with_format(SolinkJsonFormat::new().with_timestamp(false))
+        .with_env_filter("SOLINK_TEST_INIT_FILTER")
+        .to_rolling_file(&dir, Rotation::NEVER)
+        .install()
+        .unwrap();
+    info!("Filtered out");
+    warn!(x = 7, "Written");
+    drop(guards);
+
+    let file = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+    assert!(file.file_name().to_string_lossy().ends_with(".log"));
+    assert_eq!(
+        std::fs::read_to_string(file.path()).unwrap(),
+        concat!(
+            r#"{"level":"WARN","target":"init","#,
+            r#""message":"Written","x":7}"#,
+            "\n",
+        ),
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let error = init().install().unwrap_err();
+    assert!(matches!(error, InitError::Install(_)), "{}", error);
+}